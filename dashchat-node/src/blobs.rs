@@ -0,0 +1,420 @@
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use p2panda_core::Hash;
+use p2panda_core::cbor::{EncodeError, decode_cbor, encode_cbor};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::chat::ChatId;
+use crate::db::{Database, DbError};
+use crate::keyring::{DataKey, DecryptionError};
+
+/// Plaintext bytes per chunk, few enough for a chunk to fit in a gossip
+/// message.
+pub(crate) const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Chunk hashes per page of a manifest, few enough for a page to fit in a
+/// gossip message.
+pub(crate) const PAGE_CHUNKS: usize = 256;
+
+/// Pages per manifest. The manifest itself would fit four times as many,
+/// but a blob this large already takes minutes to fetch over gossip.
+pub(crate) const MAX_PAGES: usize = 64;
+
+/// The largest file which can be shared as a blob, 128 MiB.
+pub const MAX_BLOB_SIZE: usize = CHUNK_SIZE * PAGE_CHUNKS * MAX_PAGES;
+
+/// Manifests of the blobs we have, or are downloading.
+const KV_MANIFESTS: &str = "blobs/manifests";
+/// Pages of manifests, by blob and index.
+const KV_PAGES: &str = "blobs/pages";
+/// Encrypted chunks, by blob and index.
+const KV_CHUNKS: &str = "blobs/chunks";
+/// Which chunks of each blob we have, so that telling what is missing
+/// doesn't take decrypting every chunk.
+const KV_RECEIVED: &str = "blobs/received";
+/// Downloads which haven't completed yet, with the chat to fetch them from.
+const KV_DOWNLOADS: &str = "blobs/downloads";
+
+/// How many chunks a blob has, and the hashes of the pages which list them.
+///
+/// A blob is addressed by the hash of its manifest, and a page by its hash
+/// in the manifest, so that every page and chunk can be checked on its own
+/// as it arrives. Every page but the last lists [`PAGE_CHUNKS`] chunks.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub chunks: u32,
+    pub pages: Vec<Hash>,
+}
+
+impl Manifest {
+    pub(crate) fn hash(&self) -> Result<Hash, EncodeError> {
+        Ok(Hash::new(encode_cbor(self)?))
+    }
+
+    /// Whether the pages are as many as the chunks need, and no more than
+    /// we accept.
+    pub(crate) fn is_valid(&self) -> bool {
+        self.pages.len() <= MAX_PAGES
+            && self.pages.len() == (self.chunks as usize).div_ceil(PAGE_CHUNKS)
+    }
+
+    /// How many chunks a page lists.
+    pub(crate) fn page_len(&self, page: u32) -> usize {
+        (self.chunks as usize)
+            .saturating_sub(page as usize * PAGE_CHUNKS)
+            .min(PAGE_CHUNKS)
+    }
+}
+
+/// The hashes of some of a blob's encrypted chunks, in order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Page {
+    pub chunks: Vec<Hash>,
+}
+
+impl Page {
+    pub(crate) fn hash(&self) -> Result<Hash, EncodeError> {
+        Ok(Hash::new(encode_cbor(self)?))
+    }
+}
+
+/// A blob, encrypted and split up to be stored and sent.
+pub(crate) struct EncryptedBlob {
+    pub manifest: Manifest,
+    pub pages: Vec<Page>,
+    pub chunks: Vec<Vec<u8>>,
+}
+
+/// Split `plaintext` into chunks and encrypt each with `key`.
+pub(crate) fn encrypt(key: &DataKey, plaintext: &[u8]) -> Result<EncryptedBlob, EncodeError> {
+    let chunks: Vec<Vec<u8>> = plaintext
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| key.seal(chunk))
+        .collect();
+    let pages: Vec<Page> = chunks
+        .chunks(PAGE_CHUNKS)
+        .map(|chunks| Page {
+            chunks: chunks.iter().map(Hash::new).collect(),
+        })
+        .collect();
+    let manifest = Manifest {
+        chunks: chunks.len() as u32,
+        pages: pages.iter().map(Page::hash).collect::<Result<_, _>>()?,
+    };
+    Ok(EncryptedBlob {
+        manifest,
+        pages,
+        chunks,
+    })
+}
+
+/// Decrypt and join the chunks of a blob.
+pub(crate) fn decrypt(
+    key: &DataKey,
+    chunks: impl IntoIterator<Item = Vec<u8>>,
+) -> Result<Vec<u8>, DecryptionError> {
+    let mut plaintext = vec![];
+    for chunk in chunks {
+        plaintext.extend(key.open(&chunk)?);
+    }
+    Ok(plaintext)
+}
+
+/// How nodes fetch blobs from each other over a chat's gossip overlay.
+///
+/// Chunks are already encrypted with the blob's own key, which only members
+/// of the chat learn, so these messages are not encrypted again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum BlobMessage {
+    /// Ask whoever has a blob for its manifest, or for some of its pages or
+    /// chunks.
+    Want {
+        hash: Hash,
+        manifest: bool,
+        pages: Vec<u32>,
+        chunks: Vec<u32>,
+    },
+    Manifest {
+        hash: Hash,
+        manifest: Manifest,
+    },
+    Page {
+        hash: Hash,
+        index: u32,
+        page: Page,
+    },
+    Chunk {
+        hash: Hash,
+        index: u32,
+        #[serde(with = "crate::util::bytes")]
+        bytes: Vec<u8>,
+    },
+}
+
+impl BlobMessage {
+    /// The part of a blob this message sends in answer to a
+    /// [`BlobMessage::Want`], unless it is one.
+    pub(crate) fn answers(&self) -> Option<(Hash, BlobItem)> {
+        match self {
+            Self::Want { .. } => None,
+            Self::Manifest { hash, .. } => Some((*hash, BlobItem::Manifest)),
+            Self::Page { hash, index, .. } => Some((*hash, BlobItem::Page(*index))),
+            Self::Chunk { hash, index, .. } => Some((*hash, BlobItem::Chunk(*index))),
+        }
+    }
+}
+
+/// A part of a blob which can be asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum BlobItem {
+    Manifest,
+    Page(u32),
+    Chunk(u32),
+}
+
+/// How much of a blob we have, sent whenever a download makes progress.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobProgress {
+    pub hash: [u8; 32],
+    pub received: usize,
+    pub total: usize,
+}
+
+impl BlobProgress {
+    pub fn is_complete(&self) -> bool {
+        self.received == self.total
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Chunk(#[serde(with = "crate::util::bytes")] Vec<u8>);
+
+/// One bit per chunk of a blob, set once we have the chunk.
+#[derive(Default, Serialize, Deserialize)]
+struct Received(#[serde(with = "crate::util::bytes")] Vec<u8>);
+
+impl Received {
+    fn contains(&self, index: u32) -> bool {
+        self.0
+            .get(index as usize / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+
+    fn insert(&mut self, index: u32) {
+        let byte = index as usize / 8;
+        if self.0.len() <= byte {
+            self.0.resize(byte + 1, 0);
+        }
+        self.0[byte] |= 1 << (index % 8);
+    }
+
+    fn count(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+}
+
+/// Where blobs are kept, either in memory or in the key/value table of the
+/// database.
+#[derive(Clone, Debug)]
+pub(crate) enum BlobStore {
+    Memory(Arc<RwLock<HashMap<(&'static str, Vec<u8>), Vec<u8>>>>),
+    Sqlite(Database),
+}
+
+impl BlobStore {
+    pub(crate) fn memory() -> Self {
+        Self::Memory(Default::default())
+    }
+
+    fn get<K: Serialize, V: DeserializeOwned>(
+        &self,
+        namespace: &'static str,
+        key: &K,
+    ) -> Result<Option<V>, DbError> {
+        match self {
+            Self::Memory(map) => map
+                .read()
+                .expect("blob store lock poisoned")
+                .get(&(namespace, encode_cbor(key)?))
+                .map(|value| Ok(decode_cbor(value.as_slice())?))
+                .transpose(),
+            Self::Sqlite(db) => db.get(namespace, key),
+        }
+    }
+
+    fn put<K: Serialize, V: Serialize>(
+        &self,
+        namespace: &'static str,
+        key: &K,
+        value: &V,
+    ) -> Result<(), DbError> {
+        match self {
+            Self::Memory(map) => {
+                map.write()
+                    .expect("blob store lock poisoned")
+                    .insert((namespace, encode_cbor(key)?), encode_cbor(value)?);
+                Ok(())
+            }
+            Self::Sqlite(db) => db.put(namespace, key, value),
+        }
+    }
+
+    fn remove<K: Serialize>(&self, namespace: &'static str, key: &K) -> Result<(), DbError> {
+        match self {
+            Self::Memory(map) => {
+                map.write()
+                    .expect("blob store lock poisoned")
+                    .remove(&(namespace, encode_cbor(key)?));
+            }
+            Self::Sqlite(db) => {
+                db.remove(namespace, key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn list<K: DeserializeOwned, V: DeserializeOwned>(
+        &self,
+        namespace: &'static str,
+    ) -> Result<Vec<(K, V)>, DbError> {
+        match self {
+            Self::Memory(map) => map
+                .read()
+                .expect("blob store lock poisoned")
+                .iter()
+                .filter(|((ns, _), _)| *ns == namespace)
+                .map(|((_, key), value)| {
+                    Ok((decode_cbor(key.as_slice())?, decode_cbor(value.as_slice())?))
+                })
+                .collect(),
+            Self::Sqlite(db) => db.list(namespace),
+        }
+    }
+
+    pub(crate) fn manifest(&self, hash: Hash) -> Result<Option<Manifest>, DbError> {
+        self.get(KV_MANIFESTS, &hash)
+    }
+
+    pub(crate) fn put_manifest(&self, hash: Hash, manifest: &Manifest) -> Result<(), DbError> {
+        self.put(KV_MANIFESTS, &hash, manifest)
+    }
+
+    pub(crate) fn page(&self, hash: Hash, index: u32) -> Result<Option<Page>, DbError> {
+        self.get(KV_PAGES, &(hash, index))
+    }
+
+    pub(crate) fn put_page(&self, hash: Hash, index: u32, page: &Page) -> Result<(), DbError> {
+        self.put(KV_PAGES, &(hash, index), page)
+    }
+
+    /// The hash of a chunk, if we have the page which lists it.
+    pub(crate) fn chunk_hash(&self, hash: Hash, index: u32) -> Result<Option<Hash>, DbError> {
+        let page = index as usize / PAGE_CHUNKS;
+        Ok(self
+            .page(hash, page as u32)?
+            .and_then(|page| page.chunks.get(index as usize % PAGE_CHUNKS).copied()))
+    }
+
+    pub(crate) fn chunk(&self, hash: Hash, index: u32) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self
+            .get::<_, Chunk>(KV_CHUNKS, &(hash, index))?
+            .map(|chunk| chunk.0))
+    }
+
+    pub(crate) fn put_chunk(&self, hash: Hash, index: u32, bytes: Vec<u8>) -> Result<(), DbError> {
+        self.put(KV_CHUNKS, &(hash, index), &Chunk(bytes))?;
+        let mut received = self.received(hash)?;
+        received.insert(index);
+        self.put(KV_RECEIVED, &hash, &received)
+    }
+
+    /// Whether we have a chunk, without reading it.
+    pub(crate) fn has_chunk(&self, hash: Hash, index: u32) -> Result<bool, DbError> {
+        Ok(self.received(hash)?.contains(index))
+    }
+
+    fn received(&self, hash: Hash) -> Result<Received, DbError> {
+        Ok(self.get(KV_RECEIVED, &hash)?.unwrap_or_default())
+    }
+
+    /// Store a whole blob which we created ourselves.
+    pub(crate) fn put_blob(&self, hash: Hash, blob: EncryptedBlob) -> Result<(), DbError> {
+        let mut received = Received::default();
+        for (index, bytes) in blob.chunks.into_iter().enumerate() {
+            self.put(KV_CHUNKS, &(hash, index as u32), &Chunk(bytes))?;
+            received.insert(index as u32);
+        }
+        self.put(KV_RECEIVED, &hash, &received)?;
+        for (index, page) in blob.pages.iter().enumerate() {
+            self.put_page(hash, index as u32, page)?;
+        }
+        self.put_manifest(hash, &blob.manifest)
+    }
+
+    /// The indices of the pages of a blob we don't have yet.
+    pub(crate) fn missing_pages(
+        &self,
+        hash: Hash,
+        manifest: &Manifest,
+    ) -> Result<Vec<u32>, DbError> {
+        let mut missing = vec![];
+        for index in 0..manifest.pages.len() as u32 {
+            if self.page(hash, index)?.is_none() {
+                missing.push(index);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// The indices of the chunks of a blob we don't have yet.
+    pub(crate) fn missing(&self, hash: Hash, manifest: &Manifest) -> Result<Vec<u32>, DbError> {
+        let received = self.received(hash)?;
+        Ok((0..manifest.chunks)
+            .filter(|index| !received.contains(*index))
+            .collect())
+    }
+
+    /// How much of a blob we have, if we know its manifest.
+    pub(crate) fn progress(&self, hash: Hash) -> Result<Option<BlobProgress>, DbError> {
+        let Some(manifest) = self.manifest(hash)? else {
+            return Ok(None);
+        };
+        let total = manifest.chunks as usize;
+        Ok(Some(BlobProgress {
+            hash: *hash.as_bytes(),
+            received: self.received(hash)?.count().min(total),
+            total,
+        }))
+    }
+
+    /// The contents of a blob, if we have all of it.
+    pub(crate) fn read(&self, hash: Hash) -> Result<Option<Vec<Vec<u8>>>, DbError> {
+        let Some(manifest) = self.manifest(hash)? else {
+            return Ok(None);
+        };
+        let mut chunks = vec![];
+        for index in 0..manifest.chunks {
+            match self.chunk(hash, index)? {
+                Some(chunk) => chunks.push(chunk),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(chunks))
+    }
+
+    pub(crate) fn downloads(&self) -> Result<Vec<(Hash, ChatId)>, DbError> {
+        self.list(KV_DOWNLOADS)
+    }
+
+    pub(crate) fn put_download(&self, hash: Hash, chat_id: ChatId) -> Result<(), DbError> {
+        self.put(KV_DOWNLOADS, &hash, &chat_id)
+    }
+
+    pub(crate) fn remove_download(&self, hash: Hash) -> Result<(), DbError> {
+        self.remove(KV_DOWNLOADS, &hash)
+    }
+}
@@ -0,0 +1,100 @@
+use super::*;
+
+#[test]
+fn test_blob_roundtrip() {
+    let key = DataKey::random();
+    let plaintext: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+    let blob = encrypt(&key, &plaintext).unwrap();
+    assert_eq!(blob.manifest.chunks, 3);
+    assert_eq!(blob.manifest.pages.len(), 1);
+    assert!(blob.manifest.is_valid());
+    let manifest = blob.manifest.clone();
+    let hash = manifest.hash().unwrap();
+
+    let store = BlobStore::memory();
+    store.put_manifest(hash, &manifest).unwrap();
+    assert_eq!(store.missing_pages(hash, &manifest).unwrap(), vec![0]);
+    assert_eq!(store.chunk_hash(hash, 1).unwrap(), None);
+    store.put_page(hash, 0, &blob.pages[0]).unwrap();
+    assert_eq!(
+        store.chunk_hash(hash, 1).unwrap(),
+        Some(Hash::new(&blob.chunks[1]))
+    );
+    store.put_chunk(hash, 1, blob.chunks[1].clone()).unwrap();
+    assert_eq!(store.missing(hash, &manifest).unwrap(), vec![0, 2]);
+    assert_eq!(
+        store.progress(hash).unwrap().map(|p| (p.received, p.total)),
+        Some((1, 3))
+    );
+    assert!(store.read(hash).unwrap().is_none());
+
+    store.put_blob(hash, blob).unwrap();
+    let stored = store.read(hash).unwrap().unwrap();
+    assert_eq!(decrypt(&key, stored.clone()).unwrap(), plaintext);
+    assert!(decrypt(&DataKey::random(), stored).is_err());
+}
+
+#[test]
+fn test_blob_spans_pages() {
+    let key = DataKey::random();
+    let plaintext = vec![7; CHUNK_SIZE * PAGE_CHUNKS + 1];
+    let blob = encrypt(&key, &plaintext).unwrap();
+    assert_eq!(blob.manifest.chunks as usize, PAGE_CHUNKS + 1);
+    assert_eq!(blob.manifest.pages.len(), 2);
+    assert_eq!(blob.manifest.page_len(0), PAGE_CHUNKS);
+    assert_eq!(blob.manifest.page_len(1), 1);
+    assert_eq!(blob.pages[1].chunks.len(), 1);
+    assert!(blob.manifest.is_valid());
+
+    let last = blob.chunks[PAGE_CHUNKS].clone();
+    let hash = blob.manifest.hash().unwrap();
+    let store = BlobStore::memory();
+    store.put_blob(hash, blob).unwrap();
+    assert_eq!(
+        store.chunk_hash(hash, PAGE_CHUNKS as u32).unwrap(),
+        Some(Hash::new(&last))
+    );
+    let stored = store.read(hash).unwrap().unwrap();
+    assert_eq!(decrypt(&key, stored).unwrap(), plaintext);
+
+    let too_many = Manifest {
+        chunks: (PAGE_CHUNKS * (MAX_PAGES + 1)) as u32,
+        pages: vec![hash; MAX_PAGES + 1],
+    };
+    assert!(!too_many.is_valid());
+    let too_few = Manifest {
+        chunks: PAGE_CHUNKS as u32 + 1,
+        pages: vec![hash],
+    };
+    assert!(!too_few.is_valid());
+}
+
+#[test]
+fn test_chunk_fits_in_gossip_message() {
+    let key = DataKey::random();
+    let blob = encrypt(&key, &vec![0xff; CHUNK_SIZE]).unwrap();
+    let hash = blob.manifest.hash().unwrap();
+    let message = BlobMessage::Chunk {
+        hash,
+        index: 0,
+        bytes: blob.chunks[0].clone(),
+    };
+    assert!(encode_cbor(&message).unwrap().len() < CHUNK_SIZE + 1024);
+
+    let page = Page {
+        chunks: vec![hash; PAGE_CHUNKS],
+    };
+    let message = BlobMessage::Page {
+        hash,
+        index: 0,
+        page,
+    };
+    assert!(encode_cbor(&message).unwrap().len() < 10 * 1000);
+
+    let manifest = Manifest {
+        chunks: (PAGE_CHUNKS * MAX_PAGES) as u32,
+        pages: vec![hash; MAX_PAGES],
+    };
+    let message = BlobMessage::Manifest { hash, manifest };
+    assert!(encode_cbor(&message).unwrap().len() < 10 * 1000);
+}
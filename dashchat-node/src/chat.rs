@@ -1,37 +1,695 @@
+mod group_info;
 mod message;
+mod reactions;
+mod role;
+mod search;
+mod signal;
+mod timeline;
+pub use group_info::{GroupInfo, GroupInfoUpdate};
 pub use message::*;
+pub use role::Role;
+pub use search::{SearchFilters, SearchResult, SnippetPart};
+pub use signal::*;
+pub use timeline::MessageCursor;
+
+use reactions::Reactions;
+pub(crate) use role::RoleHistory;
+pub(crate) use search::words;
+use timeline::Receipts;
+pub(crate) use timeline::Timeline;
 
 #[cfg(test)]
 mod tests;
 
-use std::{collections::BTreeSet, convert::Infallible, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::Infallible,
+    ops::Bound,
+    str::FromStr,
+};
 
 use p2panda_net::ToNetwork;
 use serde::{Deserialize, Serialize};
 
+use crate::PK;
+
+/// Sort key of a message in the timeline, matching the `Ord` of [`ChatMessage`].
+pub(crate) type MessageKey = (u64, PK, MessageId);
+
+/// The kinds of position a member can mark in a chat's timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum MarkerKind {
+    Received,
+    Read,
+}
+
+impl MarkerKind {
+    pub(crate) fn content(self, up_to: MessageId) -> ChatMessageContent {
+        match self {
+            MarkerKind::Received => ChatMessageContent::ReceivedMarker { up_to },
+            MarkerKind::Read => ChatMessageContent::ReadMarker { up_to },
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Chat {
     pub(crate) id: ChatId,
 
+    /// Our own public key, to tell our messages apart.
+    me: PK,
+
     /// The gossip overlay sender for this chat.
     pub(crate) sender: tokio::sync::mpsc::Sender<ToNetwork>,
 
-    /// The processed decrypted messages for this chat.
-    pub(crate) messages: BTreeSet<ChatMessage>,
+    /// The processed decrypted messages for this chat, in timeline order.
+    messages: BTreeMap<MessageKey, ChatMessage>,
+
+    /// Where to find each message in `messages`.
+    index: HashMap<MessageId, MessageKey>,
+
+    /// The replies to each message, which may arrive before it.
+    replies: HashMap<MessageId, BTreeSet<MessageId>>,
+
+    /// The messages as they are shown, readable without locking the chat.
+    timeline: Timeline,
+
+    reactions: Reactions,
+
+    /// Edits, keyed by the message they edit, in the order they were made.
+    /// They may arrive before the original, so their author is only checked
+    /// against it when they are applied.
+    edits: HashMap<MessageId, BTreeMap<(u64, u64, MessageId), ChatMessage>>,
+
+    /// Requests to delete a message, keyed by the message. They may also
+    /// arrive before the original.
+    tombstones: HashMap<MessageId, Vec<Tombstone>>,
+
+    /// Messages which have been deleted, with their author.
+    deleted: HashMap<MessageId, PK>,
+
+    /// Messages whose payloads are to be forgotten: deleted and expired
+    /// messages, and their edits.
+    purged: HashSet<MessageId>,
+
+    /// When we last pruned our own log, and whether messages have been
+    /// purged since, which may still be carried in it.
+    pruned_at: Option<u64>,
+    unpruned: bool,
+
+    /// Each member's latest marker of each kind: the timestamp and id of the
+    /// marker message, and the message marked up to.
+    markers: HashMap<(MarkerKind, PK), (u64, MessageId, MessageId)>,
+
+    /// Our own messages which haven't been sent to the network yet.
+    pending: HashSet<MessageId>,
+
+    /// When the latest signal of each kind from each member expires.
+    signals: HashMap<(PK, Signal), u64>,
+
+    /// The latest retention setting: when it was made, by which message, and
+    /// how many seconds messages are kept, if not forever.
+    retention: Option<(u64, MessageId, Option<u64>)>,
+
+    /// The latest group info: its version, when it was set, by which
+    /// message, and the info itself.
+    group_info: Option<(u64, u64, MessageId, GroupInfo)>,
+
+    /// Whether each moderation setting turned moderation on. Deletions
+    /// name the setting they were made under.
+    moderation_settings: HashMap<MessageId, bool>,
+
+    /// The latest moderation setting: when it was made, by which message,
+    /// and whether moderation is on.
+    moderation: Option<(u64, MessageId, bool)>,
+
+    /// Every change to the members' roles, to judge their messages by.
+    pub(crate) roles: RoleHistory,
 
     /// Whether I have been removed from this chat.
     pub(crate) removed: bool,
 }
 
 impl Chat {
-    pub fn new(id: ChatId, sender: tokio::sync::mpsc::Sender<ToNetwork>) -> Self {
+    pub fn new(id: ChatId, me: PK, sender: tokio::sync::mpsc::Sender<ToNetwork>) -> Self {
         Self {
             id,
+            me,
             sender,
-            messages: BTreeSet::new(),
+            messages: BTreeMap::new(),
+            index: HashMap::new(),
+            replies: HashMap::new(),
+            timeline: Timeline::default(),
+            reactions: Reactions::default(),
+            edits: HashMap::new(),
+            tombstones: HashMap::new(),
+            deleted: HashMap::new(),
+            purged: HashSet::new(),
+            pruned_at: None,
+            unpruned: false,
+            markers: HashMap::new(),
+            pending: HashSet::new(),
+            signals: HashMap::new(),
+            retention: None,
+            group_info: None,
+            moderation_settings: HashMap::new(),
+            moderation: None,
+            roles: RoleHistory::default(),
             removed: false,
         }
     }
+
+    /// Add a message to the timeline, or apply it to the message it targets.
+    /// `role` is the author's role where they sent it, which decides whether
+    /// a deletion is theirs to make.
+    ///
+    /// Returns the ids of messages which have been deleted as a result, and
+    /// whose payloads should be purged.
+    pub(crate) fn insert_message(&mut self, message: ChatMessage, role: Role) -> Vec<MessageId> {
+        let purge = self.apply_message(message, role);
+        self.forget(&purge);
+        purge
+    }
+
+    /// The messages whose payloads are to be forgotten, so that they mustn't
+    /// be published again.
+    pub(crate) fn purged(&self) -> HashSet<MessageId> {
+        self.purged.clone()
+    }
+
+    /// Forget a message which arrived after it should already have expired.
+    ///
+    /// Returns its id, as its payload should be purged.
+    pub(crate) fn discard(&mut self, id: MessageId) -> Vec<MessageId> {
+        self.forget(&[id]);
+        vec![id]
+    }
+
+    /// Whether our own log may carry messages which have been purged since
+    /// we last pruned it, at least `interval` seconds before `now`.
+    pub(crate) fn prune_due(&self, now: u64, interval: u64) -> bool {
+        self.unpruned
+            && self
+                .pruned_at
+                .is_none_or(|at| at.saturating_add(interval) <= now)
+    }
+
+    pub(crate) fn set_pruned(&mut self, now: u64) {
+        self.pruned_at = Some(now);
+        self.unpruned = false;
+    }
+
+    fn forget(&mut self, ids: &[MessageId]) {
+        if !ids.is_empty() {
+            self.purged.extend(ids.iter().copied());
+            self.unpruned = true;
+        }
+    }
+
+    fn apply_message(&mut self, message: ChatMessage, role: Role) -> Vec<MessageId> {
+        match message.content {
+            ChatMessageContent::Reaction {
+                target,
+                emoji,
+                remove,
+                clock,
+            } => {
+                self.reactions.apply(
+                    target,
+                    emoji,
+                    message.author,
+                    (clock, message.timestamp, message.id),
+                    !remove,
+                );
+                self.refresh_message(&target);
+            }
+            ChatMessageContent::Edit {
+                target, revision, ..
+            } => {
+                if self.deleted.get(&target) == Some(&message.author) {
+                    return vec![message.id];
+                }
+                self.edits
+                    .entry(target)
+                    .or_default()
+                    .insert((revision, message.timestamp, message.id), message);
+                self.refresh(&target);
+            }
+            ChatMessageContent::ReadMarker { up_to } => {
+                self.set_marker(MarkerKind::Read, &message, up_to)
+            }
+            ChatMessageContent::ReceivedMarker { up_to } => {
+                self.set_marker(MarkerKind::Received, &message, up_to)
+            }
+            ChatMessageContent::Delete { target, moderation } => {
+                // Like the author's role, whether they were an admin is
+                // judged where they made the deletion
+                let tombstone = Tombstone {
+                    deleter: message.author,
+                    admin: role == Role::Admin,
+                    moderation,
+                };
+                return self.delete_message(target, tombstone);
+            }
+            ChatMessageContent::Moderation { enabled } => {
+                self.moderation_settings.insert(message.id, enabled);
+                let setting = (message.timestamp, message.id, enabled);
+                if self.moderation.is_none_or(|latest| setting > latest) {
+                    self.moderation = Some(setting);
+                }
+                return self.retry_tombstones();
+            }
+            ChatMessageContent::Retention { lifetime } => {
+                let setting = (message.timestamp, message.id, lifetime);
+                if self.retention.is_none_or(|latest| setting > latest) {
+                    self.retention = Some(setting);
+                }
+            }
+            ChatMessageContent::GroupInfo { info, version } => {
+                let setting = (version, message.timestamp, message.id, info);
+                if self
+                    .group_info
+                    .as_ref()
+                    .is_none_or(|latest| setting > *latest)
+                {
+                    self.group_info = Some(setting);
+                }
+            }
+            _ => {
+                if self.is_deleted(&message) {
+                    self.deleted.insert(message.id, message.author);
+                    return vec![message.id];
+                }
+                let (id, key) = (message.id, (message.timestamp, message.author, message.id));
+                if let Some(parent) = message.content.reply_to() {
+                    self.replies.entry(*parent).or_default().insert(id);
+                }
+                self.index.insert(id, key);
+                self.messages.insert(key, message);
+                self.refresh(&id);
+
+                // Markers which arrived before the message now count
+                if self.markers.values().any(|(_, _, up_to)| *up_to == id) {
+                    for own in self.own_messages_between(None, key) {
+                        self.refresh_message(&own);
+                    }
+                }
+            }
+        }
+        vec![]
+    }
+
+    fn set_marker(&mut self, kind: MarkerKind, message: &ChatMessage, up_to: MessageId) {
+        let received_before = self.marker_position(MarkerKind::Received, message.author);
+        let marker = (message.timestamp, message.id, up_to);
+        let latest = self.markers.entry((kind, message.author)).or_insert(marker);
+        if marker > *latest {
+            *latest = marker;
+        }
+
+        let received = self.marker_position(MarkerKind::Received, message.author);
+        if message.author == self.me {
+            return;
+        }
+        if let Some(up_to) = received.filter(|_| received > received_before) {
+            for own in self.own_messages_between(received_before, up_to) {
+                self.refresh_message(&own);
+            }
+        }
+    }
+
+    /// Where in the timeline a member's marker of the given kind is, if we
+    /// have the message it points to. Having read a message implies having
+    /// received it.
+    pub(crate) fn marker_position(&self, kind: MarkerKind, member: PK) -> Option<MessageKey> {
+        let position = |kind| {
+            self.markers
+                .get(&(kind, member))
+                .and_then(|(_, _, up_to)| self.position(up_to))
+        };
+        match kind {
+            MarkerKind::Read => position(MarkerKind::Read),
+            MarkerKind::Received => position(MarkerKind::Received).max(position(MarkerKind::Read)),
+        }
+    }
+
+    /// Members whose marker of the given kind is at or after a message.
+    pub(crate) fn marked_by(&self, kind: MarkerKind, id: &MessageId) -> Option<Vec<PK>> {
+        let key = self.index.get(id)?;
+        let members = self
+            .markers
+            .keys()
+            .map(|(_, member)| *member)
+            .collect::<BTreeSet<_>>();
+        Some(
+            members
+                .into_iter()
+                .filter(|member| {
+                    self.marker_position(kind, *member)
+                        .is_some_and(|position| position >= *key)
+                })
+                .collect(),
+        )
+    }
+
+    /// Note whether one of our own messages is still waiting to be sent to
+    /// the network.
+    pub(crate) fn set_pending(&mut self, id: MessageId, pending: bool) {
+        if pending {
+            self.pending.insert(id);
+        } else {
+            self.pending.remove(&id);
+        }
+        self.refresh_message(&id);
+    }
+
+    /// Remember a signal until it expires.
+    pub(crate) fn set_signal(&mut self, author: PK, signal: Signal, expires_at: u64) {
+        let latest = self.signals.entry((author, signal)).or_insert(expires_at);
+        *latest = expires_at.max(*latest);
+    }
+
+    /// Signals which haven't expired by `now`, forgetting those which have.
+    pub(crate) fn signals(&mut self, now: u64) -> Vec<SignalEvent> {
+        self.signals.retain(|_, expires_at| *expires_at > now);
+        let mut signals: Vec<_> = self
+            .signals
+            .iter()
+            .map(|((author, signal), expires_at)| SignalEvent {
+                chat_id: self.id,
+                author: *author,
+                signal: *signal,
+                expires_at: *expires_at,
+            })
+            .collect();
+        signals.sort_by_key(|event| (event.author, event.signal));
+        signals
+    }
+
+    /// Our own messages in the timeline after `after`, up to and including
+    /// `up_to`.
+    pub(crate) fn own_messages_between(
+        &self,
+        after: Option<MessageKey>,
+        up_to: MessageKey,
+    ) -> Vec<MessageId> {
+        let after = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        self.messages
+            .range((after, Bound::Included(up_to)))
+            .filter(|(_, message)| message.author == self.me)
+            .map(|(_, message)| message.id)
+            .collect()
+    }
+
+    /// How far one of our own messages has got to the `members` other
+    /// members of the chat.
+    pub(crate) fn delivery_state(&self, id: &MessageId, members: usize) -> Option<DeliveryState> {
+        self.receipts(id).map(|receipts| receipts.state(members))
+    }
+
+    fn receipts(&self, id: &MessageId) -> Option<Receipts> {
+        if self.message(id)?.author != self.me {
+            return None;
+        }
+        let received_by = self
+            .marked_by(MarkerKind::Received, id)?
+            .into_iter()
+            .filter(|member| *member != self.me)
+            .count();
+        Some(Receipts {
+            pending: self.pending.contains(id),
+            received_by,
+        })
+    }
+
+    /// Hide a message and its edits, if the tombstone allows it.
+    ///
+    /// Returns the ids of messages which have been deleted, and whose
+    /// payloads should be purged.
+    fn delete_message(&mut self, target: MessageId, tombstone: Tombstone) -> Vec<MessageId> {
+        self.tombstones.entry(target).or_default().push(tombstone);
+
+        let Some(original) = self.message(&target) else {
+            return vec![];
+        };
+        if !tombstone.applies_to(original, &self.moderation_settings) {
+            tracing::warn!(?self.id, ?target, deleter = ?tombstone.deleter, "rejecting deletion");
+            return vec![];
+        }
+        self.remove_deleted(target)
+    }
+
+    /// Apply the tombstones which a moderation setting that arrived after
+    /// them now allows.
+    fn retry_tombstones(&mut self) -> Vec<MessageId> {
+        let targets = self
+            .tombstones
+            .iter()
+            .filter(|(target, tombstones)| {
+                self.message(target).is_some_and(|message| {
+                    tombstones
+                        .iter()
+                        .any(|tombstone| tombstone.applies_to(message, &self.moderation_settings))
+                })
+            })
+            .map(|(target, _)| *target)
+            .collect::<Vec<_>>();
+        targets
+            .into_iter()
+            .flat_map(|target| self.remove_deleted(target))
+            .collect()
+    }
+
+    /// Hide a message which has been deleted, and its edits.
+    fn remove_deleted(&mut self, target: MessageId) -> Vec<MessageId> {
+        let Some(original) = self.message(&target).cloned() else {
+            return vec![];
+        };
+        let key = self.index.remove(&target).expect("message is indexed");
+        self.messages.remove(&key);
+        self.deleted.insert(target, original.author);
+
+        let edits = self
+            .valid_edits(&original)
+            .map(|edit| edit.id)
+            .collect::<Vec<_>>();
+        self.edits.remove(&target);
+        self.refresh(&target);
+        let purge = std::iter::once(target).chain(edits).collect::<Vec<_>>();
+        self.forget(&purge);
+        purge
+    }
+
+    /// How many seconds messages are kept, if not forever.
+    pub(crate) fn lifetime(&self) -> Option<u64> {
+        self.retention.and_then(|(_, _, lifetime)| lifetime)
+    }
+
+    /// The message which made the retention setting in force, which has to
+    /// be kept for as long as it is.
+    pub(crate) fn retention_setting(&self) -> Option<MessageId> {
+        self.retention.map(|(_, id, _)| id)
+    }
+
+    /// The group info in force, and its version.
+    pub(crate) fn group_info(&self) -> Option<(u64, &GroupInfo)> {
+        self.group_info
+            .as_ref()
+            .map(|(version, _, _, info)| (*version, info))
+    }
+
+    /// The message which set the group info in force, which has to be kept
+    /// for as long as it is.
+    pub(crate) fn group_info_setting(&self) -> Option<MessageId> {
+        self.group_info.as_ref().map(|(_, _, id, _)| *id)
+    }
+
+    /// The moderation setting in force, if there is one: the message which
+    /// made it, and whether moderation is on.
+    pub(crate) fn moderation(&self) -> Option<(MessageId, bool)> {
+        self.moderation.map(|(_, id, enabled)| (id, enabled))
+    }
+
+    /// Every moderation setting, which deletions may name and so have to be
+    /// kept.
+    pub(crate) fn moderation_settings(&self) -> impl Iterator<Item = MessageId> + '_ {
+        self.moderation_settings.keys().copied()
+    }
+
+    /// Whether a message sent at `timestamp` has outlived the retention
+    /// setting by `now`.
+    pub(crate) fn is_expired(&self, timestamp: u64, now: u64) -> bool {
+        self.lifetime()
+            .is_some_and(|lifetime| timestamp.saturating_add(lifetime) <= now)
+    }
+
+    /// Forget the messages which have outlived the retention setting, along
+    /// with their edits.
+    ///
+    /// Returns the ids of the messages and edits which have expired, and
+    /// whose payloads should be purged.
+    pub(crate) fn expire(&mut self, now: u64) -> Vec<MessageId> {
+        let expired = self
+            .messages
+            .keys()
+            .take_while(|(timestamp, _, _)| self.is_expired(*timestamp, now))
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut purge = vec![];
+        for key in expired {
+            let original = self.messages.remove(&key).expect("message is stored");
+            self.index.remove(&original.id);
+            self.deleted.insert(original.id, original.author);
+            purge.extend(self.valid_edits(&original).map(|edit| edit.id));
+            purge.push(original.id);
+            self.edits.remove(&original.id);
+            self.refresh(&original.id);
+        }
+        self.forget(&purge);
+        purge
+    }
+
+    /// Whether a message has already been deleted by a tombstone which
+    /// arrived before it.
+    fn is_deleted(&self, message: &ChatMessage) -> bool {
+        self.tombstones
+            .get(&message.id)
+            .into_iter()
+            .flatten()
+            .any(|tombstone| tombstone.applies_to(message, &self.moderation_settings))
+    }
+
+    /// The clock for `author`'s next reaction to a message with an emoji.
+    pub(crate) fn next_reaction_clock(&self, target: &MessageId, emoji: &str, author: PK) -> u64 {
+        self.reactions.clock(target, emoji, author) + 1
+    }
+
+    pub(crate) fn message(&self, id: &MessageId) -> Option<&ChatMessage> {
+        self.index.get(id).and_then(|key| self.messages.get(key))
+    }
+
+    /// Where a message is in the timeline, if we have it.
+    pub(crate) fn position(&self, id: &MessageId) -> Option<MessageKey> {
+        self.index.get(id).copied()
+    }
+
+    /// A handle to the messages as they are shown.
+    pub(crate) fn timeline(&self) -> Timeline {
+        self.timeline.clone()
+    }
+
+    /// Bring a message, and the replies quoting it, up to date in the
+    /// timeline.
+    fn refresh(&self, id: &MessageId) {
+        self.refresh_message(id);
+        for reply in self.replies.get(id).into_iter().flatten() {
+            self.refresh_message(reply);
+        }
+    }
+
+    fn refresh_message(&self, id: &MessageId) {
+        let entry = self
+            .message(id)
+            .map(|message| (self.resolve(message), self.receipts(id)));
+        self.timeline.set(*id, entry);
+    }
+
+    /// A message and all direct and indirect replies to it, in timeline order.
+    pub(crate) fn thread(&self, root: &MessageId) -> Option<Vec<ChatMessage>> {
+        self.message(root)?;
+        let mut keys = vec![];
+        let mut queue = vec![*root];
+        while let Some(id) = queue.pop() {
+            // Replies to a deleted message are no longer part of the thread
+            if let Some(key) = self.index.get(&id) {
+                keys.push(*key);
+                queue.extend(self.replies.get(&id).into_iter().flatten());
+            }
+        }
+        keys.sort();
+
+        Some(
+            keys.into_iter()
+                .filter_map(|key| self.messages.get(&key))
+                .map(|message| self.resolve(message))
+                .collect(),
+        )
+    }
+
+    /// A message as originally sent, followed by each of its edits.
+    pub(crate) fn edit_history(&self, id: &MessageId) -> Option<Vec<ChatMessage>> {
+        let original = self.message(id)?;
+        Some(
+            std::iter::once(original)
+                .chain(self.valid_edits(original))
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Edits made to a message by its author.
+    fn valid_edits<'a>(
+        &'a self,
+        original: &'a ChatMessage,
+    ) -> impl Iterator<Item = &'a ChatMessage> + 'a {
+        self.edits
+            .get(&original.id)
+            .into_iter()
+            .flat_map(|edits| edits.values())
+            .filter(|edit| edit.author == original.author)
+    }
+
+    /// A message with its latest edit applied.
+    fn latest(&self, message: &ChatMessage) -> ChatMessage {
+        let mut message = message.clone();
+        let text = self
+            .valid_edits(&message)
+            .last()
+            .map(|edit| edit.content.text().unwrap_or_default().to_string());
+        if let Some(text) = text {
+            message.content.set_text(text);
+            message.edited = true;
+        }
+        message
+    }
+
+    fn resolve(&self, message: &ChatMessage) -> ChatMessage {
+        let mut message = self.latest(message);
+        message.quoted = message
+            .content
+            .reply_to()
+            .and_then(|id| self.message(id))
+            .map(|quoted| QuotedMessage::new(&self.latest(quoted)));
+        message.reactions = self.reactions.on(&message.id);
+        message
+    }
+}
+
+/// A request to delete a message for everyone.
+#[derive(Clone, Copy, Debug)]
+struct Tombstone {
+    deleter: PK,
+    /// Whether the deleter was an admin where they made the deletion.
+    admin: bool,
+    /// The moderation setting the deletion was made under.
+    moderation: Option<MessageId>,
+}
+
+impl Tombstone {
+    /// Anyone can delete their own messages. Admins can delete anyone's
+    /// while moderation is on, as far as they could tell: the setting they
+    /// name has to have turned it on, even if a later one turned it off.
+    fn applies_to(&self, message: &ChatMessage, moderation: &HashMap<MessageId, bool>) -> bool {
+        self.deleter == message.author
+            || (self.admin
+                && self
+                    .moderation
+                    .is_some_and(|id| moderation.get(&id) == Some(&true)))
+    }
 }
 
 #[derive(
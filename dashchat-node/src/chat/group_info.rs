@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+use super::{AttachmentRef, ChatId};
+
+/// What a group is called and what it's about, as shown to its members.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GroupInfo {
+    pub name: String,
+    pub description: String,
+    /// An image shared like an attachment, which members download with
+    /// [`crate::Node::download_attachment`].
+    pub avatar: Option<AttachmentRef>,
+}
+
+/// Sent whenever a chat's [`GroupInfo`] changes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupInfoUpdate {
+    pub chat_id: ChatId,
+    pub info: GroupInfo,
+}
@@ -1,46 +1,367 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
 
+use p2panda_core::cbor::decode_cbor;
 use serde::{Deserialize, Serialize};
 
 use crate::{Cbor, PK};
 
+use super::{ChatId, GroupInfo};
+
 /// A standalone chat message suitable for sending to the frontend.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ChatMessage {
+    /// The id of the space Application message which carried this message.
+    pub id: MessageId,
     pub content: ChatMessageContent,
     pub author: PK,
     pub timestamp: u64,
+    /// For replies, a preview of the message being replied to, if we have it.
+    pub quoted: Option<QuotedMessage>,
+    /// Who reacted to this message, by emoji.
+    pub reactions: BTreeMap<String, BTreeSet<PK>>,
+    /// Whether `content` is an edited version of what was originally sent.
+    pub edited: bool,
+    /// For our own messages, how far they have got to the other members.
+    pub delivery: Option<DeliveryState>,
+}
+
+impl ChatMessage {
+    pub(crate) fn new(id: MessageId, author: PK, payload: ChatMessagePayload) -> Self {
+        Self {
+            id,
+            content: payload.content,
+            author,
+            timestamp: payload.timestamp,
+            quoted: None,
+            reactions: BTreeMap::new(),
+            edited: false,
+            delivery: None,
+        }
+    }
+}
+
+/// How far one of our own messages has got to the other members of a chat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Stored locally, but not yet sent to anyone.
+    Pending,
+    /// Broadcast to whoever is online, but no member has confirmed it yet.
+    Gossiped,
+    /// Confirmed by `received_by` of the `members` other members.
+    Delivered { received_by: usize, members: usize },
+}
+
+/// Sent whenever the [`DeliveryState`] of one of our messages changes.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeliveryUpdate {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub state: DeliveryState,
 }
 
-impl Cbor for ChatMessage {}
+/// Maximum number of characters of a replied-to message shown in a reply.
+const SNIPPET_LEN: usize = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotedMessage {
+    pub id: MessageId,
+    pub author: PK,
+    pub snippet: String,
+}
+
+impl QuotedMessage {
+    pub(crate) fn new(message: &ChatMessage) -> Self {
+        let text = message.content.text().unwrap_or_default();
+        let mut snippet: String = text.chars().take(SNIPPET_LEN).collect();
+        if snippet.len() < text.len() {
+            snippet.push('…');
+        }
+        Self {
+            id: message.id,
+            author: message.author,
+            snippet,
+        }
+    }
+}
 
 impl PartialOrd for ChatMessage {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(
-            self.timestamp
-                .cmp(&other.timestamp)
-                .then(self.content.cmp(&other.content))
-                .then(self.author.cmp(&other.author)),
-        )
+        Some(self.cmp(other))
     }
 }
 
+/// Messages are ordered by timestamp, then author. The id only breaks ties,
+/// so that distinct messages are never collapsed.
 impl Ord for ChatMessage {
     fn cmp(&self, other: &Self) -> Ordering {
         self.timestamp
             .cmp(&other.timestamp)
-            .then(self.content.cmp(&other.content))
             .then(self.author.cmp(&other.author))
+            .then(self.id.cmp(&other.id))
     }
 }
 
-#[derive(
-    Clone, Debug, PartialEq, Eq, Serialize, Deserialize, derive_more::From, derive_more::Deref,
-)]
-pub struct ChatMessageContent(String);
+/// The [`ChatMessagePayload::version`] written by this client.
+pub(crate) const PAYLOAD_VERSION: u8 = 1;
+
+/// What is encrypted and published to the space for each chat message.
+///
+/// The id can't be part of it, since it is derived from the published
+/// message. The author is checked against the signed space message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChatMessagePayload {
+    /// Bumped when the payload changes in a way this client would misread,
+    /// as opposed to gaining a new kind of content. Only `author` and
+    /// `timestamp` are kept across versions, so that a message from a newer
+    /// version can still be shown as [`ChatMessageContent::Unknown`].
+    /// Payloads written before there were versions have none, and are
+    /// version 1.
+    #[serde(default = "first_version")]
+    pub version: u8,
+    pub content: ChatMessageContent,
+    pub author: PK,
+    pub timestamp: u64,
+}
+
+fn first_version() -> u8 {
+    1
+}
+
+impl Cbor for ChatMessagePayload {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, p2panda_core::cbor::DecodeError> {
+        /// The part of a payload which all versions share.
+        #[derive(Deserialize)]
+        struct Envelope {
+            #[serde(default = "first_version")]
+            version: u8,
+            author: PK,
+            timestamp: u64,
+        }
+
+        let envelope: Envelope = decode_cbor(bytes)?;
+        if envelope.version > PAYLOAD_VERSION {
+            return Ok(Self {
+                version: envelope.version,
+                content: ChatMessageContent::Unknown,
+                author: envelope.author,
+                timestamp: envelope.timestamp,
+            });
+        }
+        decode_cbor(bytes)
+    }
+}
+
+/// What a chat message says or does.
+///
+/// Each kind is tagged by name on the wire. Kinds are only ever added, never
+/// changed: a kind which needs a different shape gets a new name. Kinds this
+/// version doesn't know about decode as [`ChatMessageContent::Unknown`], as
+/// does all content of a newer [`ChatMessagePayload::version`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatMessageContent {
+    Text {
+        text: String,
+    },
+    Reply {
+        reply_to: MessageId,
+        text: String,
+    },
+    /// Add, or with `remove`, take back a reaction. `clock` orders the
+    /// reactions of one author to the same message with the same emoji.
+    Reaction {
+        target: MessageId,
+        emoji: String,
+        #[serde(default)]
+        remove: bool,
+        #[serde(default)]
+        clock: u64,
+    },
+    /// Replace the text of `target`. `revision` orders the edits of one
+    /// message.
+    Edit {
+        target: MessageId,
+        text: String,
+        #[serde(default)]
+        revision: u64,
+    },
+    /// Delete `target` for everyone. Deleting someone else's message names
+    /// the moderation setting which allowed it.
+    Delete {
+        target: MessageId,
+        #[serde(default)]
+        moderation: Option<MessageId>,
+    },
+    /// The author has read every message up to and including `up_to`.
+    ReadMarker {
+        up_to: MessageId,
+    },
+    /// The author's node has received every message up to and including
+    /// `up_to`.
+    ReceivedMarker {
+        up_to: MessageId,
+    },
+    Attachment {
+        attachment: AttachmentRef,
+        caption: Option<String>,
+    },
+    /// From now on, keep messages for `lifetime` seconds, or forever with
+    /// `None`. The latest setting applies.
+    Retention {
+        lifetime: Option<u64>,
+    },
+    /// From now on, let admins delete anyone's messages, or only their own.
+    /// The latest setting applies.
+    Moderation {
+        enabled: bool,
+    },
+    /// Replace the group info. `version` orders the changes, and the latest
+    /// applies.
+    GroupInfo {
+        info: GroupInfo,
+        version: u64,
+    },
+    /// A notice generated by a node rather than typed by a person, e.g. about
+    /// membership changes.
+    System {
+        text: String,
+    },
+    /// Sent by a newer client, and not understood by this one.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ChatMessageContent {
+    /// The human-readable text of this message, if it has any.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Self::Text { text }
+            | Self::Reply { text, .. }
+            | Self::Edit { text, .. }
+            | Self::System { text } => Some(text),
+            Self::Attachment { caption, .. } => caption.as_deref(),
+            Self::Reaction { .. }
+            | Self::Delete { .. }
+            | Self::ReadMarker { .. }
+            | Self::ReceivedMarker { .. }
+            | Self::Retention { .. }
+            | Self::Moderation { .. }
+            | Self::GroupInfo { .. }
+            | Self::Unknown => None,
+        }
+    }
+
+    /// Replace the human-readable text of this message, if it can have any.
+    pub(crate) fn set_text(&mut self, new_text: String) {
+        match self {
+            Self::Text { text }
+            | Self::Reply { text, .. }
+            | Self::Edit { text, .. }
+            | Self::System { text } => *text = new_text,
+            Self::Attachment { caption, .. } => *caption = Some(new_text),
+            Self::Reaction { .. }
+            | Self::Delete { .. }
+            | Self::ReadMarker { .. }
+            | Self::ReceivedMarker { .. }
+            | Self::Retention { .. }
+            | Self::Moderation { .. }
+            | Self::GroupInfo { .. }
+            | Self::Unknown => {}
+        }
+    }
+
+    /// The message this one replies to, if it is a reply.
+    pub fn reply_to(&self) -> Option<&MessageId> {
+        match self {
+            Self::Reply { reply_to, .. } => Some(reply_to),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for ChatMessageContent {
+    fn from(text: String) -> Self {
+        Self::Text { text }
+    }
+}
 
 impl From<&str> for ChatMessageContent {
     fn from(value: &str) -> Self {
-        Self(value.to_string())
+        Self::Text {
+            text: value.to_string(),
+        }
+    }
+}
+
+/// A file shared in a chat. The blob itself is stored and transferred
+/// separately, in chunks encrypted with `key`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    /// Hash of the blob's manifest, which lists the hashes of its chunks.
+    pub hash: [u8; 32],
+    pub key: [u8; 32],
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+}
+
+/// Identifies a chat message so that other messages can refer to it.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, derive_more::Deref,
+)]
+#[serde(into = "String", try_from = "String")]
+pub struct MessageId([u8; 32]);
+
+impl From<p2panda_core::Hash> for MessageId {
+    fn from(hash: p2panda_core::Hash) -> Self {
+        Self(*hash.as_bytes())
+    }
+}
+
+impl From<MessageId> for p2panda_core::Hash {
+    fn from(id: MessageId) -> Self {
+        Self::from_bytes(id.0)
+    }
+}
+
+impl From<MessageId> for String {
+    fn from(id: MessageId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for MessageId {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        MessageId::from_str(&value)
+    }
+}
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut k = self.to_string();
+        k.truncate(8);
+        write!(f, "Msg|{k}")
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = anyhow::Error;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        Ok(Self(hex::decode(id)?.try_into().map_err(|e| {
+            anyhow::anyhow!("Invalid MessageId: {e:?}")
+        })?))
     }
 }
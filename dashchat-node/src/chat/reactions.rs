@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::PK;
+
+use super::MessageId;
+
+/// Emoji reactions to the messages of a chat.
+///
+/// Each member's reaction with a given emoji to a given message is a
+/// last-writer-wins register, so concurrent adds and removes converge to
+/// the same state on every node regardless of the order they arrive in.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Reactions(HashMap<MessageId, HashMap<(String, PK), Register>>);
+
+/// Orders updates to one register: the author's own clock first, then the
+/// timestamp and id of the message which carried the update.
+pub(crate) type Version = (u64, u64, MessageId);
+
+#[derive(Clone, Debug)]
+struct Register {
+    version: Version,
+    active: bool,
+}
+
+impl Reactions {
+    pub fn apply(
+        &mut self,
+        target: MessageId,
+        emoji: String,
+        author: PK,
+        version: Version,
+        active: bool,
+    ) {
+        let register = self
+            .0
+            .entry(target)
+            .or_default()
+            .entry((emoji, author))
+            .or_insert(Register { version, active });
+        if version > register.version {
+            *register = Register { version, active };
+        }
+    }
+
+    /// The clock value of the latest update to an author's reaction, or 0 if
+    /// there is none.
+    pub fn clock(&self, target: &MessageId, emoji: &str, author: PK) -> u64 {
+        self.0
+            .get(target)
+            .and_then(|registers| registers.get(&(emoji.to_string(), author)))
+            .map(|register| register.version.0)
+            .unwrap_or_default()
+    }
+
+    /// Who currently reacts to a message, by emoji.
+    pub fn on(&self, target: &MessageId) -> BTreeMap<String, BTreeSet<PK>> {
+        let mut reactions = BTreeMap::<String, BTreeSet<PK>>::new();
+        for ((emoji, author), register) in self.0.get(target).into_iter().flatten() {
+            if register.active {
+                reactions.entry(emoji.clone()).or_default().insert(*author);
+            }
+        }
+        reactions
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+
+use p2panda_auth::group::{GroupAction, GroupMember};
+use p2panda_auth::{Access, AccessLevel};
+use p2panda_spaces::message::AuthoredMessage;
+use p2panda_spaces::{ActorId, OperationId};
+use serde::{Deserialize, Serialize};
+
+use crate::PK;
+use crate::spaces::{ArgType, ChatConditions, SpaceControlMessage, SpacesArgs};
+
+use super::ChatMessageContent;
+
+/// What a member may do in a chat, from least to most.
+///
+/// Roles are stored as the member's access to the chat's Space, so they
+/// change along with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can read messages and mark them as read, but not post.
+    ReadOnly,
+    /// Can post messages.
+    Post,
+    /// Can post messages and add members, but not remove anyone.
+    Invite,
+    /// Can do anything, including removing members and changing roles.
+    Admin,
+}
+
+impl Role {
+    pub fn access(self) -> Access<ChatConditions> {
+        match self {
+            Role::ReadOnly => Access::read(),
+            Role::Post => Access::write(),
+            Role::Invite => Access::manage().with_conditions(ChatConditions { add_only: true }),
+            Role::Admin => Access::manage(),
+        }
+    }
+
+    pub fn from_access(access: &Access<ChatConditions>) -> Self {
+        match access.level {
+            AccessLevel::Pull | AccessLevel::Read => Role::ReadOnly,
+            AccessLevel::Write => Role::Post,
+            AccessLevel::Manage if access.conditions.is_some_and(|c| c.add_only) => Role::Invite,
+            AccessLevel::Manage => Role::Admin,
+        }
+    }
+
+    /// Whether a member with this role may send a message with this content.
+    pub fn may_send(self, content: &ChatMessageContent) -> bool {
+        match content {
+            // Receipts don't add anything to the chat
+            ChatMessageContent::ReadMarker { .. } | ChatMessageContent::ReceivedMarker { .. } => {
+                true
+            }
+            ChatMessageContent::Retention { .. }
+            | ChatMessageContent::Moderation { .. }
+            | ChatMessageContent::GroupInfo { .. } => self == Role::Admin,
+            _ => self >= Role::Post,
+        }
+    }
+
+    /// Whether a member with this role may add a member with `role`.
+    /// Nobody can hand out more than they have, and only admins can make
+    /// others admins or inviters.
+    pub fn may_add(self, role: Role) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Invite => role <= Role::Post,
+            _ => false,
+        }
+    }
+
+    /// Whether a member with this role may remove members or change their
+    /// roles.
+    pub fn may_manage(self) -> bool {
+        self == Role::Admin
+    }
+}
+
+/// Every change to the roles of a chat's members, as made by the control
+/// messages of its Space.
+///
+/// Messages are judged by the roles in force where they were made, i.e. by
+/// the control messages they depend on, not by the roles at the time they
+/// happen to be processed. That way every member comes to the same decision
+/// whatever order messages arrive in, and a later change of role never
+/// rejects a message after the fact.
+#[derive(Debug, Default)]
+pub(crate) struct RoleHistory {
+    /// The dependencies of each space message, other than application
+    /// messages.
+    dependencies: HashMap<OperationId, Vec<OperationId>>,
+    /// The roles each member was given, by which control message. `None`
+    /// is a removal.
+    changes: HashMap<PK, Vec<(OperationId, Option<Role>)>>,
+    /// Control messages which their author's role didn't allow, and those
+    /// which depend on them.
+    rejected: HashSet<OperationId>,
+}
+
+impl RoleHistory {
+    /// Record a space message, unless the role of its author didn't allow
+    /// it. Returns whether it was allowed.
+    ///
+    /// The Space only checks that membership changes come from members who
+    /// manage it. The conditions of their access are up to us: members who
+    /// can invite may only add members, and with no more than [`Role::Post`].
+    pub(crate) fn record(&mut self, msg: &SpaceControlMessage) -> bool {
+        let dependencies = msg.dependencies();
+        if dependencies.iter().any(|id| self.rejected.contains(id)) {
+            self.rejected.insert(msg.id());
+            return false;
+        }
+
+        if let SpacesArgs::Auth {
+            control_message, ..
+        } = &msg.spaces_args
+        {
+            let author = self.role_at(msg.author().into(), &dependencies);
+            let allowed = match &control_message.action {
+                _ if author != Some(Role::Invite) => true,
+                GroupAction::Add { access, .. } => Role::Invite.may_add(Role::from_access(access)),
+                _ => false,
+            };
+            if !allowed {
+                self.rejected.insert(msg.id());
+                return false;
+            }
+            for (member, role) in role_changes(&control_message.action) {
+                self.changes
+                    .entry(member)
+                    .or_default()
+                    .push((msg.id(), role));
+            }
+        }
+        // Nothing depends on application messages
+        if msg.arg_type() != ArgType::Application {
+            self.dependencies.insert(msg.id(), dependencies);
+        }
+        true
+    }
+
+    /// The role of `member` as of a message with these dependencies, or
+    /// `None` if they weren't a member there.
+    ///
+    /// Concurrent changes resolve to the lesser role, as a removal wins
+    /// over concurrent changes in the Space.
+    pub(crate) fn role_at(&self, member: PK, dependencies: &[OperationId]) -> Option<Role> {
+        let past = self.ancestors(dependencies);
+        let changes = self
+            .changes
+            .get(&member)?
+            .iter()
+            .filter(|(id, _)| past.contains(id))
+            .collect::<Vec<_>>();
+        changes
+            .iter()
+            .filter(|(id, _)| {
+                !changes.iter().any(|(other, _)| {
+                    other != id && self.ancestors(&self.dependencies[other]).contains(id)
+                })
+            })
+            .map(|(_, role)| *role)
+            .min()
+            .flatten()
+    }
+
+    /// The role of a message's author where they sent it, if it allows them
+    /// to send this content. Nobody who wasn't a member there may send
+    /// anything.
+    pub(crate) fn permitted(
+        &self,
+        author: PK,
+        dependencies: &[OperationId],
+        content: &ChatMessageContent,
+    ) -> Option<Role> {
+        self.role_at(author, dependencies)
+            .filter(|role| role.may_send(content))
+    }
+
+    /// These messages and everything they depend on, as far as we have it.
+    fn ancestors(&self, dependencies: &[OperationId]) -> HashSet<OperationId> {
+        let mut past = HashSet::new();
+        let mut next = dependencies.to_vec();
+        while let Some(id) = next.pop() {
+            if past.insert(id.clone()) {
+                next.extend(self.dependencies.get(&id).into_iter().flatten().cloned());
+            }
+        }
+        past
+    }
+}
+
+/// The members whose role an action sets, and to what.
+fn role_changes(action: &GroupAction<ActorId, ChatConditions>) -> Vec<(PK, Option<Role>)> {
+    let individual = |member: &GroupMember<ActorId>| match member {
+        GroupMember::Individual(id) => Some(PK::from(*id)),
+        GroupMember::Group(_) => None,
+    };
+    match action {
+        GroupAction::Create { initial_members } => initial_members
+            .iter()
+            .filter_map(|(member, access)| {
+                individual(member).map(|pk| (pk, Some(Role::from_access(access))))
+            })
+            .collect(),
+        GroupAction::Add { member, access }
+        | GroupAction::Promote { member, access }
+        | GroupAction::Demote { member, access } => individual(member)
+            .map(|pk| (pk, Some(Role::from_access(access))))
+            .into_iter()
+            .collect(),
+        GroupAction::Remove { member } => individual(member)
+            .map(|pk| (pk, None))
+            .into_iter()
+            .collect(),
+    }
+}
@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+
+use crate::PK;
+
+use super::{ChatId, ChatMessage, MessageId};
+
+/// How many words of context to show before the first match in a snippet.
+const SNIPPET_CONTEXT: usize = 4;
+/// How many words a snippet has at most.
+const SNIPPET_WORDS: usize = 16;
+
+/// Narrows down a search. Every filter which is set must match.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchFilters {
+    /// Only search this chat.
+    pub chat_id: Option<ChatId>,
+    /// Only messages by this member.
+    pub author: Option<PK>,
+    /// Only messages sent at or after this time.
+    pub since: Option<u64>,
+    /// Only messages sent before this time.
+    pub until: Option<u64>,
+}
+
+impl SearchFilters {
+    pub(crate) fn matches(&self, message: &ChatMessage) -> bool {
+        self.author.is_none_or(|author| message.author == author)
+            && self.since.is_none_or(|since| message.timestamp >= since)
+            && self.until.is_none_or(|until| message.timestamp < until)
+    }
+}
+
+/// A message which matches a search, with the part of its text that matched.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub chat_id: ChatId,
+    pub message_id: MessageId,
+    pub author: PK,
+    pub timestamp: u64,
+    pub snippet: Vec<SnippetPart>,
+}
+
+/// A piece of a snippet, highlighted if it is a word that matched.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnippetPart {
+    pub text: String,
+    pub highlight: bool,
+}
+
+impl SearchResult {
+    pub(crate) fn new(chat_id: ChatId, message: &ChatMessage, terms: &[String]) -> Self {
+        Self {
+            chat_id,
+            message_id: message.id,
+            author: message.author,
+            timestamp: message.timestamp,
+            snippet: snippet(message.content.text().unwrap_or_default(), terms),
+        }
+    }
+}
+
+/// The words of a text as they are indexed and searched for.
+pub(crate) fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    spans(text).map(|(start, end)| text[start..end].to_lowercase())
+}
+
+/// Whether a word is matched by a search term. The last word of a query is
+/// usually still being typed, so terms match any word they are a prefix of.
+pub(crate) fn matches(word: &str, term: &str) -> bool {
+    word.starts_with(term)
+}
+
+/// Byte ranges of the words in a text.
+fn spans(text: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = None;
+    text.char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+        .filter_map(move |(i, c)| match (c.is_alphanumeric(), start) {
+            (true, None) => {
+                start = Some(i);
+                None
+            }
+            (false, Some(s)) => {
+                start = None;
+                Some((s, i))
+            }
+            _ => None,
+        })
+}
+
+/// The part of a text around the first word matching any of `terms`, split
+/// into plain and highlighted parts.
+fn snippet(text: &str, terms: &[String]) -> Vec<SnippetPart> {
+    let spans: Vec<_> = spans(text)
+        .map(|(start, end)| {
+            let word = text[start..end].to_lowercase();
+            (start, end, terms.iter().any(|term| matches(&word, term)))
+        })
+        .collect();
+    let first = spans.iter().position(|(_, _, hit)| *hit).unwrap_or(0);
+    let from = first.saturating_sub(SNIPPET_CONTEXT);
+    let to = spans.len().min(from + SNIPPET_WORDS);
+    let Some(window) = spans.get(from..to).filter(|window| !window.is_empty()) else {
+        return vec![];
+    };
+
+    let mut parts = vec![];
+    let mut push = |text: String, highlight: bool| {
+        if !text.is_empty() {
+            parts.push(SnippetPart { text, highlight });
+        }
+    };
+    let mut plain = if from > 0 {
+        "…".to_string()
+    } else {
+        String::new()
+    };
+    let mut pos = window[0].0;
+    for (start, end, hit) in window {
+        if *hit {
+            plain.push_str(&text[pos..*start]);
+            push(std::mem::take(&mut plain), false);
+            push(text[*start..*end].to_string(), true);
+            pos = *end;
+        }
+    }
+    plain.push_str(&text[pos..window[window.len() - 1].1]);
+    if to < spans.len() {
+        plain.push('…');
+    }
+    push(plain, false);
+    parts
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Cbor, PK};
+
+use super::ChatId;
+
+/// Something short-lived a member tells whoever in a chat is online right now.
+/// Signals are never stored or synced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Signal {
+    Typing,
+    Online,
+    /// Sent by a newer client, and not understood by this one.
+    #[serde(other)]
+    Unknown,
+}
+
+/// A signal received from another member, valid until `expires_at`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalEvent {
+    pub chat_id: ChatId,
+    pub author: PK,
+    pub signal: Signal,
+    pub expires_at: u64,
+}
+
+/// What is encrypted and gossiped to the space for each signal.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SignalPayload {
+    pub signal: Signal,
+    pub author: PK,
+    pub timestamp: u64,
+}
+
+impl Cbor for SignalPayload {}
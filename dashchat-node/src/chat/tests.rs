@@ -1,14 +1,34 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
 
 use p2panda_auth::Access;
+use p2panda_auth::group::GroupAction;
 use p2panda_spaces::message::AuthoredMessage;
-use p2panda_store::LogStore;
+use p2panda_store::{LogStore, OperationStore};
 
-use crate::{testing::*, *};
+use crate::{
+    chat::{ChatMessagePayload, RoleHistory},
+    network::Topic,
+    spaces::SpacesArgs,
+    testing::*,
+    *,
+};
 
 const TRACING_FILTER: &str =
     "dashchat=info,p2panda_stream=info,p2panda_auth=warn,p2panda_spaces=warn";
 
+/// Messages as every member sees them, i.e. without the delivery state which
+/// only the author has.
+async fn shared_messages(node: &TestNode, chat_id: ChatId) -> Vec<ChatMessage> {
+    let mut messages = node.get_messages(chat_id).await.unwrap();
+    for message in messages.iter_mut() {
+        message.delivery = None;
+    }
+    messages
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test_group_2() {
     crate::testing::setup_tracing(TRACING_FILTER);
@@ -28,7 +48,10 @@ async fn test_group_2() {
 
     let (chat_id, _) = alice.create_group().await.unwrap();
 
-    alice.add_member(chat_id, bob.public_key()).await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
 
     bob_rx
         .watch_for(Duration::from_secs(5), |n| {
@@ -72,8 +95,8 @@ async fn test_group_2() {
     .await
     .unwrap();
 
-    let alice_messages = alice.get_messages(chat_id).await.unwrap();
-    let bob_messages = bob.get_messages(chat_id).await.unwrap();
+    let alice_messages = shared_messages(&alice, chat_id).await;
+    let bob_messages = shared_messages(&bob, chat_id).await;
 
     assert_eq!(alice_messages, bob_messages);
     assert_eq!(
@@ -113,7 +136,10 @@ async fn test_group_3() {
     println!("==> alice creates group");
     let (chat_id, _) = alice.create_group().await.unwrap();
     println!("==> alice adds bob");
-    alice.add_member(chat_id, bob.public_key()).await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Admin)
+        .await
+        .unwrap();
 
     // Bob has joined the group via his inbox topic and is a manager
     wait_for(
@@ -146,8 +172,8 @@ async fn test_group_3() {
     consistency([&alice, &bob], &tt, &cfg).await.unwrap();
 
     assert_eq!(
-        alice.get_messages(chat_id).await.unwrap(),
-        bob.get_messages(chat_id).await.unwrap()
+        shared_messages(&alice, chat_id).await,
+        shared_messages(&bob, chat_id).await
     );
     assert_eq!(alice.get_messages(chat_id).await.unwrap().len(), 1);
 
@@ -157,13 +183,15 @@ async fn test_group_3() {
     consistency([&alice, &bob], &tt, &cfg).await.unwrap();
 
     assert_eq!(
-        alice.get_messages(chat_id).await.unwrap(),
-        bob.get_messages(chat_id).await.unwrap()
+        shared_messages(&alice, chat_id).await,
+        shared_messages(&bob, chat_id).await
     );
     assert_eq!(alice.get_messages(chat_id).await.unwrap().len(), 2);
 
     println!("==> bob adds carol");
-    bob.add_member(chat_id, carol.public_key()).await.unwrap();
+    bob.add_member(chat_id, carol.public_key(), Role::Admin)
+        .await
+        .unwrap();
 
     consistency([&alice, &bob, &carol], &tt, &cfg)
         .await
@@ -240,10 +268,1467 @@ async fn test_group_3() {
         }
     }
 
-    let alice_messages = alice.get_messages(chat_id).await.unwrap();
-    let bob_messages = bob.get_messages(chat_id).await.unwrap();
-    let carol_messages = carol.get_messages(chat_id).await.unwrap();
+    let alice_messages = shared_messages(&alice, chat_id).await;
+    let bob_messages = shared_messages(&bob, chat_id).await;
+    let carol_messages = shared_messages(&carol, chat_id).await;
 
     pretty_assertions::assert_eq!(alice_messages, bob_messages);
     pretty_assertions::assert_eq!(bob_messages, carol_messages);
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_remove_member() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, mut bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+
+    let hello = alice.send_message(chat_id, "Hello".into()).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap_or_default();
+            messages.iter().any(|m| m.id == hello.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(
+        alice
+            .remove_member(chat_id, alice.public_key())
+            .await
+            .is_err()
+    );
+    alice
+        .remove_member(chat_id, bob.public_key())
+        .await
+        .unwrap();
+
+    bob_rx
+        .watch_for(Duration::from_secs(5), |n| {
+            matches!(
+                n.payload,
+                Payload::Invitation(InvitationMessage::RemovedFromGroup(id)) if id == chat_id
+            )
+        })
+        .await
+        .unwrap();
+    let bob_actor = p2panda_spaces::ActorId::from(bob.public_key());
+    assert!(
+        !alice
+            .get_members(chat_id)
+            .await
+            .unwrap()
+            .iter()
+            .any(|(id, _)| *id == bob_actor)
+    );
+
+    // Bob's node stops following the chat
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async { (!bob.get_groups().await.unwrap().contains(&chat_id)).ok_or(()) },
+    )
+    .await
+    .unwrap();
+
+    // Bob no longer gets the chat's operations, and couldn't decrypt them
+    // if he did, as he no longer has the group secret
+    let secret = alice.send_message(chat_id, "Secret".into()).await.unwrap();
+    let topic = Topic::from(chat_id);
+    let (hash, msg) = alice
+        .op_store
+        .get_log(&alice.public_key(), &topic, None)
+        .await
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .find_map(|(header, body)| {
+            let Payload::SpaceControl(msgs) = Payload::try_from_body(body?).ok()? else {
+                return None;
+            };
+            let msg = msgs
+                .into_iter()
+                .find(|msg| MessageId::from(msg.hash) == secret.id)?;
+            Some((header.hash(), msg))
+        })
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(bob.op_store.get_operation(hash).await.unwrap().is_none());
+    // Either the message is rejected, or it waits for a secret Bob never gets
+    if let Ok(events) = bob.manager.process(&msg).await {
+        assert!(
+            !events
+                .iter()
+                .any(|event| matches!(event, p2panda_spaces::event::Event::Application { .. }))
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_messages_need_a_role() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    alice
+        .remove_member(chat_id, bob.public_key())
+        .await
+        .unwrap();
+
+    // Bob's own node won't send anything once he is removed
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async { (!bob.get_groups().await.unwrap().contains(&chat_id)).ok_or(()) },
+    )
+    .await
+    .unwrap();
+    assert!(
+        bob.send_message(chat_id, "Still here".into())
+            .await
+            .is_err()
+    );
+
+    // Judge messages as if they had been sent at each point of Alice's log
+    let topic = Topic::from(chat_id);
+    let mut roles = RoleHistory::default();
+    let (mut added, mut removed) = (vec![], vec![]);
+    let log = alice
+        .op_store
+        .get_log(&alice.public_key(), &topic, None)
+        .await
+        .unwrap()
+        .unwrap();
+    for (_, body) in log {
+        let Payload::SpaceControl(msgs) = Payload::try_from_body(body.unwrap()).unwrap() else {
+            continue;
+        };
+        for msg in msgs {
+            assert!(roles.record(&msg));
+            if let SpacesArgs::Auth {
+                control_message, ..
+            } = &msg.spaces_args
+            {
+                match &control_message.action {
+                    GroupAction::Add { .. } => added.push(msg.id()),
+                    GroupAction::Remove { .. } => removed.push(msg.id()),
+                    _ => {}
+                }
+            }
+        }
+    }
+    assert_eq!((added.len(), removed.len()), (1, 1));
+
+    let text = ChatMessageContent::from("Hello");
+    let stranger = PK::from(PrivateKey::new().public_key());
+    assert_eq!(
+        roles.permitted(bob.public_key(), &added, &text),
+        Some(Role::Post)
+    );
+    assert_eq!(roles.permitted(bob.public_key(), &removed, &text), None);
+    assert_eq!(roles.permitted(stranger, &added, &text), None);
+    assert_eq!(
+        roles.permitted(alice.public_key(), &removed, &text),
+        Some(Role::Admin)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_leave_group() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, mut bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+
+    bob_rx
+        .watch_for(Duration::from_secs(5), |n| {
+            matches!(
+                n.payload,
+                Payload::Invitation(InvitationMessage::JoinGroup(id)) if id == chat_id
+            )
+        })
+        .await
+        .unwrap();
+    let bob_actor = p2panda_spaces::ActorId::from(bob.public_key());
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let members = bob.get_members(chat_id).await.unwrap();
+            members
+                .iter()
+                .any(|(id, _)| *id == bob_actor)
+                .ok_or(members)
+        },
+    )
+    .await
+    .unwrap();
+
+    bob.leave_group(chat_id).await.unwrap();
+    assert!(!bob.get_groups().await.unwrap().contains(&chat_id));
+    assert!(bob.get_messages(chat_id).await.is_err());
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let members = alice.get_members(chat_id).await.unwrap();
+            (!members.iter().any(|(id, _)| *id == bob_actor)).ok_or(members)
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_roles() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    assert_eq!(
+        alice.get_role(chat_id, alice.public_key()).await.unwrap(),
+        Some(Role::Admin)
+    );
+    alice
+        .add_member(chat_id, bob.public_key(), Role::ReadOnly)
+        .await
+        .unwrap();
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let role = bob.get_role(chat_id, bob.public_key()).await;
+            matches!(role, Ok(Some(Role::ReadOnly))).ok_or(role)
+        },
+    )
+    .await
+    .unwrap();
+
+    // Reading is all bob can do
+    assert!(bob.send_message(chat_id, "hi".into()).await.is_err());
+    assert!(
+        bob.remove_member(chat_id, alice.public_key())
+            .await
+            .is_err()
+    );
+    assert!(
+        bob.set_role(chat_id, bob.public_key(), Role::Admin)
+            .await
+            .is_err()
+    );
+
+    alice
+        .set_role(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let role = bob.get_role(chat_id, bob.public_key()).await;
+            matches!(role, Ok(Some(Role::Post))).ok_or(role)
+        },
+    )
+    .await
+    .unwrap();
+
+    let hi = bob.send_message(chat_id, "hi".into()).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = alice.get_messages(chat_id).await.unwrap();
+            messages.iter().any(|m| m.id == hi.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+
+    // Posting doesn't extend to settings or to adding members
+    assert!(bob.set_retention(chat_id, None).await.is_err());
+    assert!(
+        bob.add_member(chat_id, alice.public_key(), Role::Post)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invite_role_is_add_only() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    let (carol, _carol_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network, &carol.network]).await;
+
+    for node in [&bob, &carol] {
+        alice.add_friend(node.me().await.unwrap()).await.unwrap();
+        node.add_friend(alice.me().await.unwrap()).await.unwrap();
+    }
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    alice
+        .add_member(chat_id, carol.public_key(), Role::Invite)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let roles = (
+                carol.get_role(chat_id, carol.public_key()).await,
+                carol.get_role(chat_id, bob.public_key()).await,
+            );
+            matches!(roles, (Ok(Some(Role::Invite)), Ok(Some(Role::Post)))).ok_or(roles)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(
+        carol
+            .remove_member(chat_id, bob.public_key())
+            .await
+            .is_err()
+    );
+
+    // Get round the check on carol's own node
+    let msgs = carol
+        .space(chat_id)
+        .await
+        .unwrap()
+        .remove(bob.public_key().into())
+        .await
+        .unwrap();
+    let header = carol
+        .author_operation(chat_id.into(), Payload::SpaceControl(msgs))
+        .await
+        .unwrap();
+    assert_eq!(
+        carol.get_role(chat_id, bob.public_key()).await.unwrap(),
+        None
+    );
+
+    let topic = Topic::from(chat_id);
+    for node in [&alice, &bob] {
+        wait_for(
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+            || async {
+                let heights = node.op_store.get_log_heights(&topic).await.unwrap();
+                heights
+                    .iter()
+                    .any(|(author, seq_num)| {
+                        *author == header.public_key && *seq_num >= header.seq_num
+                    })
+                    .ok_or(heights)
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    // Alice and bob rejected the removal, so bob can still read the chat
+    let hello = alice.send_message(chat_id, "Hello".into()).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap();
+            messages.iter().any(|m| m.id == hello.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+    for node in [&alice, &bob] {
+        assert_eq!(
+            node.get_role(chat_id, bob.public_key()).await.unwrap(),
+            Some(Role::Post)
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_group_info() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    assert_eq!(
+        alice.group_info(chat_id).await.unwrap(),
+        GroupInfo::default()
+    );
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async { bob.get_groups().await.unwrap().contains(&chat_id).ok_or(()) },
+    )
+    .await
+    .unwrap();
+
+    let mut updates = bob.subscribe_group_info();
+    let info = GroupInfo {
+        name: "Book club".into(),
+        description: "Reading the classics".into(),
+        avatar: None,
+    };
+    alice.set_group_info(chat_id, info.clone()).await.unwrap();
+    alice
+        .set_group_avatar(chat_id, "cover.png".into(), "image/png".into(), b"png")
+        .await
+        .unwrap();
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let info = bob.group_info(chat_id).await.unwrap();
+            info.avatar.is_some().ok_or(info)
+        },
+    )
+    .await
+    .unwrap();
+    let latest = bob.group_info(chat_id).await.unwrap();
+    assert_eq!(latest.name, info.name);
+    assert_eq!(latest.description, info.description);
+    assert_eq!(latest, alice.group_info(chat_id).await.unwrap());
+
+    // Each change is announced, and the latest version wins
+    let first = updates.recv().await.unwrap();
+    assert_eq!((first.chat_id, first.info), (chat_id, info));
+    assert_eq!(updates.recv().await.unwrap().info, latest);
+
+    // Only admins can change the info
+    assert!(
+        bob.set_group_info(chat_id, GroupInfo::default())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_invite() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    // Bob isn't a friend of alice's: the invite is all he has
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let invite = alice
+        .create_invite(chat_id, Role::Post, Duration::from_secs(60), true)
+        .await
+        .unwrap();
+    let invite: InviteToken = invite.to_string().parse().unwrap();
+
+    let mut forged = invite.clone();
+    forged.role = Role::Admin;
+    assert!(bob.redeem_invite(forged).await.is_err());
+
+    assert_eq!(bob.redeem_invite(invite).await.unwrap(), chat_id);
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let role = bob.get_role(chat_id, bob.public_key()).await;
+            matches!(role, Ok(Some(Role::Post))).ok_or(role)
+        },
+    )
+    .await
+    .unwrap();
+    assert!(bob.get_groups().await.unwrap().contains(&chat_id));
+
+    let expired = alice
+        .create_invite(chat_id, Role::Post, Duration::ZERO, false)
+        .await
+        .unwrap();
+    assert!(bob.redeem_invite(expired).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_chat_survives_restart() {
+    let dir = std::env::temp_dir().join(format!("dashchat-{}", ChatId::random()));
+    let config = NodeConfig::with_data_dir(&dir).with_passphrase("correct horse");
+
+    let (alice, _alice_rx) = TestNode::with_config(config.clone()).await;
+    let public_key = alice.public_key();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    // Identical messages are still distinct
+    let sent = [
+        alice.send_message(chat_id, "Hello".into()).await.unwrap(),
+        alice.send_message(chat_id, "Hello".into()).await.unwrap(),
+    ];
+    assert_ne!(sent[0].id, sent[1].id);
+    drop(alice);
+
+    // The stored identity can't be swapped for another one
+    assert!(
+        Node::new(PrivateKey::new(), config.clone(), None)
+            .await
+            .is_err()
+    );
+
+    let (alice, _alice_rx) = TestNode::with_config(config).await;
+    assert_eq!(alice.public_key(), public_key);
+    assert!(alice.get_groups().await.unwrap().contains(&chat_id));
+
+    let messages = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].content, ChatMessageContent::from("Hello"));
+    assert_eq!(
+        messages.iter().map(|m| m.id).collect::<BTreeSet<_>>(),
+        sent.iter().map(|m| m.id).collect::<BTreeSet<_>>()
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_backup_restores_identity_and_history() {
+    let dir = std::env::temp_dir().join(format!("dashchat-{}", ChatId::random()));
+    let backup = dir.join("backup");
+    let passphrase = Passphrase::from("backup passphrase");
+
+    let (alice, _alice_rx) = TestNode::with_config(NodeConfig::with_data_dir(dir.join("a"))).await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice.send_message(chat_id, "Hello".into()).await.unwrap();
+    alice.export_backup(&backup, &passphrase).await.unwrap();
+
+    let config = NodeConfig::with_data_dir(dir.join("b")).with_passphrase("new device");
+    assert!(
+        Node::import_backup(&backup, &"wrong".into(), &config)
+            .await
+            .is_err()
+    );
+    Node::import_backup(&backup, &passphrase, &config)
+        .await
+        .unwrap();
+    assert!(!dir.join("b.import").exists());
+    assert!(
+        Node::import_backup(&backup, &passphrase, &config)
+            .await
+            .is_err()
+    );
+
+    let (restored, _restored_rx) = TestNode::with_config(config).await;
+    assert_eq!(restored.public_key(), alice.public_key());
+    assert_eq!(
+        restored.get_messages(chat_id).await.unwrap(),
+        alice.get_messages(chat_id).await.unwrap()
+    );
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_unknown_message_kind_decodes() {
+    #[derive(serde::Serialize)]
+    struct FutureMessage {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<u8>,
+        content: FutureContent,
+        author: PK,
+        timestamp: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum FutureContent {
+        Poll { question: String },
+        Text { body: Vec<String> },
+    }
+
+    let author = PK::from(PrivateKey::new().public_key());
+    let encode = |version, content| {
+        p2panda_core::cbor::encode_cbor(&FutureMessage {
+            version,
+            content,
+            author,
+            timestamp: 1,
+        })
+        .unwrap()
+    };
+
+    // A kind we don't know
+    let poll = FutureContent::Poll {
+        question: "Lunch?".into(),
+    };
+    let message = ChatMessagePayload::from_bytes(&encode(Some(1), poll)).unwrap();
+    assert_eq!(message.content, ChatMessageContent::Unknown);
+    assert_eq!(message.author, author);
+
+    // A kind we know, in a newer version of the payload which changed it
+    let text = FutureContent::Text {
+        body: vec!["Hello".into()],
+    };
+    let message = ChatMessagePayload::from_bytes(&encode(Some(2), text)).unwrap();
+    assert_eq!(message.version, 2);
+    assert_eq!(message.content, ChatMessageContent::Unknown);
+    assert_eq!(message.author, author);
+
+    let text = ChatMessagePayload {
+        version: 1,
+        content: "Hello".into(),
+        author,
+        timestamp: 1,
+    };
+    assert_eq!(
+        ChatMessagePayload::from_bytes(&text.as_bytes().unwrap()).unwrap(),
+        text
+    );
+
+    // Payloads from before there were versions
+    #[derive(serde::Serialize)]
+    struct UnversionedMessage {
+        content: ChatMessageContent,
+        author: PK,
+        timestamp: u64,
+    }
+    let bytes = p2panda_core::cbor::encode_cbor(&UnversionedMessage {
+        content: "Hello".into(),
+        author,
+        timestamp: 1,
+    })
+    .unwrap();
+    assert_eq!(ChatMessagePayload::from_bytes(&bytes).unwrap(), text);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_replies_and_threads() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+
+    let root = alice.send_message(chat_id, "Lunch?".into()).await.unwrap();
+    let reply = alice
+        .send_reply(chat_id, root.id, "Pizza".into())
+        .await
+        .unwrap();
+    let nested = alice
+        .send_reply(chat_id, reply.id, "Again?".into())
+        .await
+        .unwrap();
+    alice
+        .send_message(chat_id, "Unrelated".into())
+        .await
+        .unwrap();
+
+    let quoted = reply.quoted.clone().unwrap();
+    assert_eq!(quoted.id, root.id);
+    assert_eq!(quoted.author, alice.public_key());
+    assert_eq!(quoted.snippet, "Lunch?");
+
+    let messages = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(messages.len(), 4);
+    let listed = messages.iter().find(|m| m.id == nested.id).unwrap();
+    assert_eq!(listed.quoted.as_ref().map(|q| q.id), Some(reply.id));
+
+    let thread = alice.get_thread(chat_id, root.id).await.unwrap();
+    assert_eq!(
+        thread.iter().map(|m| m.id).collect::<BTreeSet<_>>(),
+        BTreeSet::from([root.id, reply.id, nested.id])
+    );
+
+    let missing: MessageId = "00".repeat(32).parse().unwrap();
+    assert!(
+        alice
+            .send_reply(chat_id, missing, "?".into())
+            .await
+            .is_err()
+    );
+    assert!(alice.get_thread(chat_id, missing).await.is_err());
+}
+
+#[test]
+fn test_reactions_converge() {
+    let author = PK::from(PrivateKey::new().public_key());
+    let target: MessageId = "11".repeat(32).parse().unwrap();
+    let ids: Vec<MessageId> = ["22", "33", "44"]
+        .iter()
+        .map(|b| b.repeat(32).parse().unwrap())
+        .collect();
+    // add, remove, add again, all within the same second
+    let updates = [
+        ((1, 5, ids[0]), true),
+        ((2, 5, ids[1]), false),
+        ((3, 5, ids[2]), true),
+    ];
+
+    let orders: [[usize; 3]; 3] = [[0, 1, 2], [2, 1, 0], [1, 2, 0]];
+    for order in orders {
+        let mut reactions = super::reactions::Reactions::default();
+        for i in order {
+            let (version, active) = updates[i];
+            reactions.apply(target, "👍".into(), author, version, active);
+        }
+        assert_eq!(
+            reactions.on(&target),
+            BTreeMap::from([("👍".to_string(), BTreeSet::from([author]))])
+        );
+        assert_eq!(reactions.clock(&target, "👍", author), 3);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_react_and_unreact() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let message = alice.send_message(chat_id, "Hello".into()).await.unwrap();
+
+    alice.react(chat_id, message.id, "🎉".into()).await.unwrap();
+    alice.react(chat_id, message.id, "👍".into()).await.unwrap();
+    alice
+        .unreact(chat_id, message.id, "🎉".into())
+        .await
+        .unwrap();
+
+    let messages = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].reactions,
+        BTreeMap::from([("👍".to_string(), BTreeSet::from([alice.public_key()]))])
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_edit_message() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let message = alice.send_message(chat_id, "Helo".into()).await.unwrap();
+
+    alice
+        .edit_message(chat_id, message.id, "Hello".into())
+        .await
+        .unwrap();
+    alice
+        .edit_message(chat_id, message.id, "Hello!".into())
+        .await
+        .unwrap();
+
+    let messages = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].edited);
+    assert_eq!(messages[0].content, ChatMessageContent::from("Hello!"));
+
+    let history = alice.get_edit_history(chat_id, message.id).await.unwrap();
+    assert_eq!(
+        history
+            .iter()
+            .map(|m| m.content.text().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["Helo", "Hello", "Hello!"]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_message_pages() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    for i in 0..5 {
+        alice
+            .send_message(chat_id, format!("{i}").into())
+            .await
+            .unwrap();
+    }
+    let all = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(all.len(), 5);
+    let ids = |messages: Vec<ChatMessage>| messages.iter().map(|m| m.id).collect::<Vec<_>>();
+    let page = |cursor, limit| alice.get_messages_page(chat_id, cursor, limit);
+
+    assert_eq!(
+        ids(alice.get_latest_messages(chat_id, 2).await.unwrap()),
+        ids(all[3..].to_vec())
+    );
+    assert_eq!(
+        ids(page(
+            MessageCursor::Before {
+                message_id: all[3].id
+            },
+            2
+        )
+        .await
+        .unwrap()),
+        ids(all[1..3].to_vec())
+    );
+    assert_eq!(
+        ids(page(
+            MessageCursor::After {
+                message_id: all[0].id
+            },
+            10
+        )
+        .await
+        .unwrap()),
+        ids(all[1..].to_vec())
+    );
+    assert_eq!(
+        ids(page(MessageCursor::AfterTime { timestamp: 0 }, 3)
+            .await
+            .unwrap()),
+        ids(all[..3].to_vec())
+    );
+    assert!(
+        page(
+            MessageCursor::BeforeTime {
+                timestamp: all[0].timestamp
+            },
+            10
+        )
+        .await
+        .unwrap()
+        .is_empty()
+    );
+
+    let unknown = MessageCursor::Before {
+        message_id: "00".repeat(32).parse().unwrap(),
+    };
+    assert!(page(unknown, 10).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_search() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (work, _) = alice.create_group().await.unwrap();
+    let (home, _) = alice.create_group().await.unwrap();
+    let noon = alice
+        .send_message(work, "Meeting at noon".into())
+        .await
+        .unwrap();
+    let lunch = alice
+        .send_message(work, "Lunch after the meeting?".into())
+        .await
+        .unwrap();
+    alice
+        .send_message(home, "Meetings, meetings...".into())
+        .await
+        .unwrap();
+
+    let search = |query: &'static str, filters| alice.search(query, filters);
+    assert_eq!(
+        search("meet", SearchFilters::default())
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+
+    let in_work = SearchFilters {
+        chat_id: Some(work),
+        ..Default::default()
+    };
+    let results = search("MEET", in_work.clone()).await.unwrap();
+    assert_eq!(
+        results.iter().map(|r| r.message_id).collect::<Vec<_>>(),
+        vec![lunch.id, noon.id]
+    );
+
+    let results = search("noon meeting", in_work.clone()).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(
+        results[0].snippet,
+        vec![
+            SnippetPart {
+                text: "Meeting".into(),
+                highlight: true
+            },
+            SnippetPart {
+                text: " at ".into(),
+                highlight: false
+            },
+            SnippetPart {
+                text: "noon".into(),
+                highlight: true
+            },
+        ]
+    );
+
+    let by_someone_else = SearchFilters {
+        author: Some(PK::from(PrivateKey::new().public_key())),
+        ..Default::default()
+    };
+    assert!(search("meet", by_someone_else).await.unwrap().is_empty());
+
+    // Only the current text of messages is found
+    alice
+        .edit_message(work, noon.id, "Call at noon".into())
+        .await
+        .unwrap();
+    alice.delete_message(work, lunch.id).await.unwrap();
+    assert!(search("meet", in_work.clone()).await.unwrap().is_empty());
+    assert_eq!(search("call", in_work).await.unwrap().len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_delete_message_purges_payload() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let topic = Topic::from(chat_id);
+
+    let kept = alice.send_message(chat_id, "Keep".into()).await.unwrap();
+    let deleted = alice.send_message(chat_id, "Oops".into()).await.unwrap();
+    alice
+        .edit_message(chat_id, deleted.id, "Oops!".into())
+        .await
+        .unwrap();
+    let purged_before = alice
+        .op_store
+        .operations([&topic])
+        .unwrap()
+        .iter()
+        .filter(|(_, _, _, body)| body.is_none())
+        .count();
+
+    alice.delete_message(chat_id, deleted.id).await.unwrap();
+
+    let messages = alice.get_messages(chat_id).await.unwrap();
+    assert_eq!(
+        messages.iter().map(|m| m.id).collect::<Vec<_>>(),
+        vec![kept.id]
+    );
+
+    // The message and its edit are gone from the op store
+    let purged_after = alice
+        .op_store
+        .operations([&topic])
+        .unwrap()
+        .iter()
+        .filter(|(_, _, _, body)| body.is_none())
+        .count();
+    assert_eq!(purged_after, purged_before + 2);
+
+    assert!(alice.delete_message(chat_id, deleted.id).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_moderation() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let role = bob.get_role(chat_id, bob.public_key()).await;
+            matches!(role, Ok(Some(Role::Post))).ok_or(role)
+        },
+    )
+    .await
+    .unwrap();
+
+    let spam = bob.send_message(chat_id, "Spam".into()).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = alice.get_messages(chat_id).await.unwrap();
+            messages.iter().any(|m| m.id == spam.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+
+    // Moderation is off until an admin turns it on
+    assert!(!alice.get_moderation(chat_id).await.unwrap());
+    assert!(alice.delete_message(chat_id, spam.id).await.is_err());
+    assert!(bob.set_moderation(chat_id, true).await.is_err());
+
+    alice.set_moderation(chat_id, true).await.unwrap();
+    assert!(alice.get_moderation(chat_id).await.unwrap());
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async { bob.get_moderation(chat_id).await.unwrap().ok_or(()) },
+    )
+    .await
+    .unwrap();
+
+    alice.delete_message(chat_id, spam.id).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap();
+            (!messages.iter().any(|m| m.id == spam.id)).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+
+    // Moderation doesn't extend to those who aren't admins
+    let hello = alice.send_message(chat_id, "Hello".into()).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap();
+            messages.iter().any(|m| m.id == hello.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+    assert!(bob.delete_message(chat_id, hello.id).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_disappearing_messages() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    let topic = Topic::from(chat_id);
+
+    let lifetime = Duration::from_secs(3);
+    alice.set_retention(chat_id, Some(lifetime)).await.unwrap();
+    let secret = alice.send_message(chat_id, "Secret".into()).await.unwrap();
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap_or_default();
+            messages.iter().any(|m| m.id == secret.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(bob.get_retention(chat_id).await.unwrap(), Some(lifetime));
+
+    // Both nodes forget the message, and Alice prunes her log down to a
+    // single operation which keeps the space and the setting
+    for node in [&alice, &bob] {
+        wait_for(
+            Duration::from_millis(100),
+            Duration::from_secs(15),
+            || async {
+                let messages = node.get_messages(chat_id).await.unwrap();
+                let alice_ops = node
+                    .op_store
+                    .operations([&topic])
+                    .unwrap()
+                    .into_iter()
+                    .filter(|(_, _, header, _)| PK::from(header.public_key) == alice.public_key())
+                    .map(|(_, _, header, _)| header.extensions.unwrap().prune)
+                    .collect::<Vec<_>>();
+                (messages.is_empty() && alice_ops == vec![true]).ok_or((messages, alice_ops))
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(node.get_retention(chat_id).await.unwrap(), Some(lifetime));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pruning_forgets_deleted_messages() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let topic = Topic::from(chat_id);
+
+    // Alice's latest pruning operation, and the messages carried from it on
+    let carried = || async {
+        let log = alice
+            .op_store
+            .get_log(&alice.public_key(), &topic, None)
+            .await
+            .unwrap()
+            .unwrap_or_default();
+        let mut pruning = None;
+        let mut carried: Vec<MessageId> = vec![];
+        for (header, body) in log {
+            if header.extensions.as_ref().unwrap().prune {
+                pruning = Some(header.hash());
+                carried.clear();
+            }
+            if let Some(Payload::SpaceControl(msgs)) =
+                body.map(|body| Payload::try_from_body(body).unwrap())
+            {
+                carried.extend(msgs.into_iter().map(|msg| MessageId::from(msg.hash)));
+            }
+        }
+        (pruning, carried)
+    };
+
+    // Pruning happens at most every half lifetime
+    let lifetime = Duration::from_secs(12);
+    alice.set_retention(chat_id, Some(lifetime)).await.unwrap();
+    alice.send_message(chat_id, "Old".into()).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(9)).await;
+    let kept = alice.send_message(chat_id, "Kept".into()).await.unwrap();
+
+    // Once the oldest message expires, the other is carried over
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(15),
+        || async {
+            let (pruning, carried) = carried().await;
+            (pruning.is_some() && carried.contains(&kept.id)).ok_or(carried)
+        },
+    )
+    .await
+    .unwrap();
+    let (first, _) = carried().await;
+
+    // Deleting a message carried over can't purge the operation carrying it,
+    // but the next pruning leaves it out, well before it would expire
+    alice.delete_message(chat_id, kept.id).await.unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(15),
+        || async {
+            let (pruning, _) = carried().await;
+            (pruning != first).ok_or(pruning)
+        },
+    )
+    .await
+    .unwrap();
+    assert!(timestamp_now() < kept.timestamp + lifetime.as_secs());
+    assert!(!carried().await.1.contains(&kept.id));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_read_markers_are_coalesced() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    let topic = Topic::from(chat_id);
+
+    let first = alice.send_message(chat_id, "One".into()).await.unwrap();
+    let second = alice.send_message(chat_id, "Two".into()).await.unwrap();
+    let third = alice.send_message(chat_id, "Three".into()).await.unwrap();
+    let ops_before = alice.op_store.operations([&topic]).unwrap().len();
+
+    alice.mark_read(chat_id, first.id).await.unwrap();
+    alice.mark_read(chat_id, second.id).await.unwrap();
+    alice.mark_read(chat_id, first.id).await.unwrap();
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(5),
+        || async {
+            let read_by = alice.get_read_by(chat_id, second.id).await.unwrap();
+            read_by.contains(&alice.public_key()).ok_or(read_by)
+        },
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        alice.op_store.operations([&topic]).unwrap().len(),
+        ops_before + 1
+    );
+    assert_eq!(
+        alice.get_read_by(chat_id, first.id).await.unwrap(),
+        vec![alice.public_key()]
+    );
+    assert!(
+        alice
+            .get_read_by(chat_id, third.id)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_received_markers_are_coalesced() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let role = bob.get_role(chat_id, bob.public_key()).await;
+            matches!(role, Ok(Some(Role::Post))).ok_or(role)
+        },
+    )
+    .await
+    .unwrap();
+
+    let topic = Topic::from(chat_id);
+    let own_ops = |node: &TestNode| {
+        node.op_store
+            .operations([&topic])
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, header, _)| PK::from(header.public_key) == node.public_key())
+            .count()
+    };
+    let ops_before = own_ops(&bob);
+
+    let mut sent = vec![];
+    for text in ["One", "Two", "Three", "Four", "Five"] {
+        sent.push(alice.send_message(chat_id, text.into()).await.unwrap());
+    }
+    let delivered = Some(DeliveryState::Delivered {
+        received_by: 1,
+        members: 1,
+    });
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = alice.get_messages(chat_id).await.unwrap();
+            messages
+                .iter()
+                .filter(|m| sent.iter().any(|s| s.id == m.id))
+                .all(|m| m.delivery == delivered)
+                .ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+
+    // One marker acknowledged all five messages
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert_eq!(own_ops(&bob), ops_before + 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_delivery_state() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+
+    let mut updates = alice.subscribe_delivery();
+    let sent = alice.send_message(chat_id, "Hello".into()).await.unwrap();
+    let update = updates.recv().await.unwrap();
+    assert_eq!(update.message_id, sent.id);
+    assert_eq!(update.state, DeliveryState::Gossiped);
+
+    // Bob's node acknowledges the message without Bob reading it
+    let delivered = DeliveryState::Delivered {
+        received_by: 1,
+        members: 1,
+    };
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = alice.get_messages(chat_id).await.unwrap();
+            let delivery = messages.iter().find(|m| m.id == sent.id).unwrap().delivery;
+            (delivery == Some(delivered)).ok_or(delivery)
+        },
+    )
+    .await
+    .unwrap();
+
+    let update = updates.recv().await.unwrap();
+    assert_eq!((update.message_id, update.state), (sent.id, delivered));
+    assert!(
+        alice
+            .get_read_by(chat_id, sent.id)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    assert!(
+        bob.get_messages(chat_id)
+            .await
+            .unwrap()
+            .iter()
+            .all(|m| m.delivery.is_none())
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_signals_are_ephemeral() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async { bob.get_groups().await.unwrap().contains(&chat_id).ok_or(()) },
+    )
+    .await
+    .unwrap();
+
+    let topic = Topic::from(chat_id);
+    let own_ops = |node: &TestNode| {
+        node.op_store
+            .operations([&topic])
+            .unwrap()
+            .into_iter()
+            .filter(|(_, _, header, _)| PK::from(header.public_key) == node.public_key())
+            .count()
+    };
+    let ops_before = [own_ops(&alice), own_ops(&bob)];
+    let mut signals = alice.subscribe_signals();
+
+    // Gossip may not be ready right away, so keep typing until Alice notices
+    wait_for(
+        Duration::from_millis(500),
+        Duration::from_secs(10),
+        || async {
+            bob.send_signal(chat_id, Signal::Typing).await.unwrap();
+            let signals = alice.get_signals(chat_id).await.unwrap();
+            (!signals.is_empty()).ok_or(signals)
+        },
+    )
+    .await
+    .unwrap();
+
+    let event = signals.recv().await.unwrap();
+    assert_eq!(
+        (event.chat_id, event.author, event.signal),
+        (chat_id, bob.public_key(), Signal::Typing)
+    );
+    assert!(bob.get_signals(chat_id).await.unwrap().is_empty());
+
+    // Neither node appended anything to its log
+    assert_eq!([own_ops(&alice), own_ops(&bob)], ops_before);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_attachment_transfer() {
+    let (alice, _alice_rx) = TestNode::new().await;
+    let (bob, _bob_rx) = TestNode::new().await;
+    introduce_and_wait([&alice.network, &bob.network]).await;
+
+    alice.add_friend(bob.me().await.unwrap()).await.unwrap();
+    bob.add_friend(alice.me().await.unwrap()).await.unwrap();
+    let (chat_id, _) = alice.create_group().await.unwrap();
+    alice
+        .add_member(chat_id, bob.public_key(), Role::Post)
+        .await
+        .unwrap();
+
+    // Larger than a gossip message, and not a whole number of chunks
+    let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+    let sent = alice
+        .send_attachment(
+            chat_id,
+            "noise.bin".into(),
+            "application/octet-stream".into(),
+            &data,
+            Some("Have a look".into()),
+        )
+        .await
+        .unwrap();
+    let ChatMessageContent::Attachment {
+        attachment,
+        caption,
+    } = &sent.content
+    else {
+        panic!("not an attachment: {:?}", sent.content);
+    };
+    assert_eq!(caption.as_deref(), Some("Have a look"));
+    assert_eq!(
+        alice.get_attachment(attachment).await.unwrap(),
+        Some(data.clone())
+    );
+
+    wait_for(
+        Duration::from_millis(100),
+        Duration::from_secs(10),
+        || async {
+            let messages = bob.get_messages(chat_id).await.unwrap_or_default();
+            messages.iter().any(|m| m.id == sent.id).ok_or(messages)
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(bob.get_attachment(attachment).await.unwrap(), None);
+
+    let mut progress = bob.subscribe_blob_progress();
+    bob.download_attachment(chat_id, attachment).await.unwrap();
+    loop {
+        let progress = tokio::time::timeout(Duration::from_secs(30), progress.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(progress.hash, attachment.hash);
+        if progress.is_complete() {
+            break;
+        }
+    }
+    assert_eq!(bob.get_attachment(attachment).await.unwrap(), Some(data));
+
+    assert!(
+        alice
+            .send_attachment(
+                chat_id,
+                "huge.bin".into(),
+                "application/octet-stream".into(),
+                &vec![0; MAX_BLOB_SIZE + 1],
+                None,
+            )
+            .await
+            .is_err()
+    );
+}
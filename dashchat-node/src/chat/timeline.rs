@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ChatMessage, DeliveryState, MessageId, MessageKey,
+    search::{self, SearchFilters},
+};
+
+/// Where a page of messages starts. The message or time of the cursor itself
+/// is never part of the page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageCursor {
+    /// The most recent messages.
+    Latest,
+    /// The messages just before a message.
+    Before { message_id: MessageId },
+    /// The messages just after a message.
+    After { message_id: MessageId },
+    /// The messages just before a time.
+    BeforeTime { timestamp: u64 },
+    /// The messages just after a time.
+    AfterTime { timestamp: u64 },
+}
+
+/// What we know about the delivery of one of our own messages, short of how
+/// many members the chat has.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Receipts {
+    pub pending: bool,
+    /// How many other members have received the message.
+    pub received_by: usize,
+}
+
+impl Receipts {
+    pub(crate) fn state(self, members: usize) -> DeliveryState {
+        match self {
+            Receipts { pending: true, .. } => DeliveryState::Pending,
+            Receipts { received_by: 0, .. } => DeliveryState::Gossiped,
+            Receipts { received_by, .. } => DeliveryState::Delivered {
+                received_by,
+                members,
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Messages with edits, quotes and reactions applied, in timeline order.
+    messages: BTreeMap<MessageKey, (ChatMessage, Option<Receipts>)>,
+    keys: HashMap<MessageId, MessageKey>,
+    /// The messages each word of their text appears in.
+    words: BTreeMap<String, BTreeSet<MessageKey>>,
+}
+
+impl Entries {
+    fn index(&mut self, key: MessageKey, message: &ChatMessage, add: bool) {
+        for word in search::words(message.content.text().unwrap_or_default()) {
+            if add {
+                self.words.entry(word).or_default().insert(key);
+            } else if let Some(keys) = self.words.get_mut(&word) {
+                keys.remove(&key);
+                if keys.is_empty() {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+}
+
+/// A chat's timeline as it is shown, kept up to date by the [`super::Chat`]
+/// as messages arrive.
+///
+/// It has a lock of its own, so that it can be read while the chat is busy
+/// processing operations.
+#[derive(Clone, Default)]
+pub(crate) struct Timeline(Arc<RwLock<Entries>>);
+
+impl std::fmt::Debug for Timeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timeline({} messages)", self.len())
+    }
+}
+
+impl Timeline {
+    fn len(&self) -> usize {
+        self.0
+            .read()
+            .expect("timeline lock poisoned")
+            .messages
+            .len()
+    }
+
+    /// Replace a message, or remove it with `None`.
+    pub(crate) fn set(&self, id: MessageId, entry: Option<(ChatMessage, Option<Receipts>)>) {
+        let mut entries = self.0.write().expect("timeline lock poisoned");
+        if let Some(key) = entries.keys.remove(&id) {
+            if let Some((message, _)) = entries.messages.remove(&key) {
+                entries.index(key, &message, false);
+            }
+        }
+        if let Some((message, receipts)) = entry {
+            let key = (message.timestamp, message.author, message.id);
+            entries.index(key, &message, true);
+            entries.keys.insert(id, key);
+            entries.messages.insert(key, (message, receipts));
+        }
+    }
+
+    /// Up to `limit` messages from `cursor` on, in timeline order, with the
+    /// delivery state of our own messages among `members` other members.
+    ///
+    /// Returns `None` if the cursor is a message we don't have.
+    pub(crate) fn page(
+        &self,
+        cursor: MessageCursor,
+        limit: usize,
+        members: usize,
+    ) -> Option<Vec<ChatMessage>> {
+        let entries = self.0.read().expect("timeline lock poisoned");
+        let key = |id| entries.keys.get(id).copied();
+        let before = |bound| entries.messages.range((Bound::Unbounded, bound)).rev();
+        let after = |bound| entries.messages.range((bound, Bound::Unbounded));
+
+        let mut page: Vec<_> = match cursor {
+            MessageCursor::Latest => before(Bound::Unbounded).take(limit).collect(),
+            MessageCursor::Before { message_id } => before(Bound::Excluded(key(&message_id)?))
+                .take(limit)
+                .collect(),
+            MessageCursor::After { message_id } => after(Bound::Excluded(key(&message_id)?))
+                .take(limit)
+                .collect(),
+            MessageCursor::BeforeTime { timestamp } => before(Bound::Unbounded)
+                .skip_while(|((time, _, _), _)| *time >= timestamp)
+                .take(limit)
+                .collect(),
+            MessageCursor::AfterTime { timestamp } => after(Bound::Unbounded)
+                .skip_while(|((time, _, _), _)| *time <= timestamp)
+                .take(limit)
+                .collect(),
+        };
+        page.sort_by_key(|(key, _)| *key);
+
+        Some(
+            page.into_iter()
+                .map(|(_, (message, receipts))| {
+                    let mut message = message.clone();
+                    message.delivery = receipts.map(|receipts| receipts.state(members));
+                    message
+                })
+                .collect(),
+        )
+    }
+
+    /// The messages which have a word matching each of `terms` and pass
+    /// `filters`, in timeline order.
+    pub(crate) fn search(&self, terms: &[String], filters: &SearchFilters) -> Vec<ChatMessage> {
+        let entries = self.0.read().expect("timeline lock poisoned");
+        let mut found: Option<BTreeSet<MessageKey>> = None;
+        for term in terms {
+            let keys: BTreeSet<_> = entries
+                .words
+                .range::<str, _>((Bound::Included(term.as_str()), Bound::Unbounded))
+                .take_while(|(word, _)| search::matches(word, term))
+                .flat_map(|(_, keys)| keys.iter().copied())
+                .collect();
+            found = Some(match found {
+                Some(found) => found.intersection(&keys).copied().collect(),
+                None => keys,
+            });
+        }
+
+        found
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|key| entries.messages.get(key))
+            .map(|(message, _)| message)
+            .filter(|message| filters.matches(message))
+            .cloned()
+            .collect()
+    }
+}
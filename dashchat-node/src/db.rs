@@ -0,0 +1,233 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use p2panda_core::cbor::{DecodeError, EncodeError, decode_cbor, encode_cbor};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::keyring::{DataKey, DecryptionError};
+
+/// Bumped whenever stored data changes shape in a way older databases
+/// can't be read with.
+const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS operations (
+        hash        BLOB PRIMARY KEY NOT NULL,
+        log_id      BLOB NOT NULL,
+        public_key  BLOB NOT NULL,
+        seq_num     INTEGER NOT NULL,
+        header      BLOB NOT NULL,
+        body        BLOB
+    );
+
+    CREATE INDEX IF NOT EXISTS operations_by_log
+        ON operations (public_key, log_id, seq_num);
+
+    CREATE TABLE IF NOT EXISTS kv (
+        namespace   TEXT NOT NULL,
+        key         BLOB NOT NULL,
+        value       BLOB NOT NULL,
+        PRIMARY KEY (namespace, key)
+    );
+";
+
+#[derive(Debug, derive_more::Display, derive_more::Error, derive_more::From)]
+pub enum DbError {
+    #[display("database error: {_0}")]
+    Sqlite(rusqlite::Error),
+    #[display("encode error: {_0}")]
+    Encode(EncodeError),
+    #[display("decode error: {_0}")]
+    Decode(DecodeError),
+    #[display("{_0}")]
+    Decrypt(DecryptionError),
+}
+
+/// A handle to the node's SQLite database, shared by all persistent stores.
+///
+/// All access goes through a single connection behind a mutex. Queries are
+/// short and never held across an await point.
+///
+/// Every stored value (operation headers and bodies, key/value entries) is
+/// encrypted with the [`DataKey`]. Columns which are looked up by, such as
+/// public keys, log ids and key/value keys, hold keyed hashes instead, so
+/// that the database doesn't give away who we talk to. The real keys are
+/// sealed along with the values. Only operation hashes, sequence numbers
+/// and namespace names are stored in the clear.
+#[derive(Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+    data_key: DataKey,
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Database")
+    }
+}
+
+impl Database {
+    pub fn open(path: impl AsRef<Path>, data_key: DataKey) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        tracing::debug!(?path, "opening database");
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            let empty: bool = conn.query_row(
+                "SELECT NOT EXISTS (SELECT 1 FROM operations)
+                    AND NOT EXISTS (SELECT 1 FROM kv)",
+                [],
+                |row| row.get(0),
+            )?;
+            if !empty {
+                return Err(anyhow::anyhow!(
+                    "database has schema version {version}, expected {SCHEMA_VERSION}: {}",
+                    path.display()
+                ));
+            }
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            data_key,
+        })
+    }
+
+    pub(crate) fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().expect("database mutex poisoned")
+    }
+
+    pub(crate) fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        self.data_key.seal(plaintext)
+    }
+
+    pub(crate) fn unseal(&self, sealed: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        self.data_key.open(sealed)
+    }
+
+    /// A keyed hash to store in place of a lookup column, see
+    /// [`DataKey::keyed_hash`].
+    pub(crate) fn lookup_key(&self, column: &str, value: &[u8]) -> Vec<u8> {
+        self.data_key.keyed_hash(column, value).to_vec()
+    }
+
+    fn kv_key(&self, namespace: &str, key: &[u8]) -> Vec<u8> {
+        self.lookup_key(&format!("kv/{namespace}"), key)
+    }
+
+    /// Get a CBOR-encoded value from the key/value table.
+    pub(crate) fn get<K: Serialize, V: DeserializeOwned>(
+        &self,
+        namespace: &str,
+        key: &K,
+    ) -> Result<Option<V>, DbError> {
+        let value: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                params![namespace, self.kv_key(namespace, &encode_cbor(key)?)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        value
+            .map(|value| Ok(decode_cbor(self.open_entry(&value)?.value.as_slice())?))
+            .transpose()
+    }
+
+    /// Insert or overwrite a value in the key/value table.
+    pub(crate) fn put<K: Serialize, V: Serialize>(
+        &self,
+        namespace: &str,
+        key: &K,
+        value: &V,
+    ) -> Result<(), DbError> {
+        self.put_entry(namespace, &encode_cbor(key)?, &encode_cbor(value)?)
+    }
+
+    /// Remove a value from the key/value table, returning whether it existed.
+    pub(crate) fn remove<K: Serialize>(&self, namespace: &str, key: &K) -> Result<bool, DbError> {
+        let removed = self.conn().execute(
+            "DELETE FROM kv WHERE namespace = ?1 AND key = ?2",
+            params![namespace, self.kv_key(namespace, &encode_cbor(key)?)],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// All entries in a namespace of the key/value table.
+    pub(crate) fn list<K: DeserializeOwned, V: DeserializeOwned>(
+        &self,
+        namespace: &str,
+    ) -> Result<Vec<(K, V)>, DbError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT value FROM kv WHERE namespace = ?1")?;
+        let rows = stmt
+            .query_map(params![namespace], |row| row.get::<_, Vec<u8>>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|sealed| {
+                let entry = self.open_entry(&sealed)?;
+                Ok((
+                    decode_cbor(entry.key.as_slice())?,
+                    decode_cbor(entry.value.as_slice())?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Every entry of the key/value table, decrypted, as
+    /// `(namespace, key, value)`.
+    pub(crate) fn entries(&self) -> Result<Vec<(String, Vec<u8>, Vec<u8>)>, DbError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT namespace, value FROM kv")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, Vec<u8>)>, _>>()?;
+        rows.into_iter()
+            .map(|(namespace, sealed)| {
+                let entry = self.open_entry(&sealed)?;
+                Ok((namespace, entry.key, entry.value))
+            })
+            .collect()
+    }
+
+    /// Insert an entry as returned by [`Database::entries`].
+    pub(crate) fn put_entry(
+        &self,
+        namespace: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), DbError> {
+        let entry = Entry {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        self.conn().execute(
+            "INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)",
+            params![
+                namespace,
+                self.kv_key(namespace, key),
+                self.seal(&encode_cbor(&entry)?)
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn open_entry(&self, sealed: &[u8]) -> Result<Entry, DbError> {
+        Ok(decode_cbor(self.unseal(sealed)?.as_slice())?)
+    }
+}
+
+/// What is sealed into the value column of the key/value table. The key
+/// column only holds a keyed hash, so the key itself is kept here.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "crate::util::bytes")]
+    key: Vec<u8>,
+    #[serde(with = "crate::util::bytes")]
+    value: Vec<u8>,
+}
@@ -0,0 +1,60 @@
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, anyhow};
+use p2panda_core::PrivateKey;
+
+use crate::keyring::DataKey;
+
+/// Load the node's private key from `path`, or generate one and save it there
+/// if the file does not exist yet.
+///
+/// The key file is encrypted with the data key and only readable by the
+/// current user.
+pub fn load_or_create_private_key(path: &Path, data_key: &DataKey) -> anyhow::Result<PrivateKey> {
+    if path.exists() {
+        let sealed = std::fs::read(path).context("read private key")?;
+        let bytes: [u8; 32] = data_key
+            .open(&sealed)
+            .context("decrypt private key")?
+            .try_into()
+            .map_err(|_| anyhow!("private key file is corrupt: {}", path.display()))?;
+        return Ok(PrivateKey::from_bytes(&bytes));
+    }
+
+    tracing::info!(?path, "no private key found, generating a new identity");
+    let private_key = PrivateKey::new();
+    write_secret(path, &data_key.seal(private_key.as_bytes()))?;
+    Ok(private_key)
+}
+
+/// Atomically write a file which only the current user may read.
+pub(crate) fn write_secret(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp).context("create secret file")?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path).context("move secret file into place")?;
+    Ok(())
+}
+
+fn create_private_dir(dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).context("create data directory")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use p2panda_core::cbor::{decode_cbor, encode_cbor};
+use p2panda_core::{PrivateKey, Signature};
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{ChatId, Role};
+use crate::spaces::MemberCode;
+use crate::{Cbor, PK};
+
+/// Lets whoever holds it ask to join a chat, by sending it to the inbox of
+/// the member who made it. Their node adds the holder if it is still valid.
+///
+/// Shared as a hex string, e.g. in a link or QR code.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InviteToken {
+    pub chat_id: ChatId,
+    /// The member who made the invite, and who will add the holder.
+    pub inviter: MemberCode,
+    /// The role the holder gets in the chat.
+    pub role: Role,
+    /// When the invite stops working, in seconds since the epoch.
+    pub expires_at: u64,
+    /// Whether only the first holder to use it gets in.
+    pub single_use: bool,
+    /// Tells invites apart, so that they can be used up.
+    pub nonce: [u8; 16],
+    signature: Signature,
+}
+
+impl Cbor for InviteToken {}
+
+impl InviteToken {
+    pub(crate) fn new(
+        private_key: &PrivateKey,
+        inviter: MemberCode,
+        chat_id: ChatId,
+        role: Role,
+        expires_at: u64,
+        single_use: bool,
+    ) -> anyhow::Result<Self> {
+        let nonce = rand::random();
+        let terms = signed_bytes(
+            chat_id,
+            inviter.public_key(),
+            role,
+            expires_at,
+            single_use,
+            nonce,
+        )?;
+        Ok(Self {
+            chat_id,
+            inviter,
+            role,
+            expires_at,
+            single_use,
+            nonce,
+            signature: private_key.sign(&terms),
+        })
+    }
+
+    pub fn inviter(&self) -> PK {
+        self.inviter.public_key()
+    }
+
+    /// Check that the inviter made this invite and that it hasn't expired
+    /// by `now`.
+    pub fn verify(&self, now: u64) -> anyhow::Result<()> {
+        let terms = signed_bytes(
+            self.chat_id,
+            self.inviter(),
+            self.role,
+            self.expires_at,
+            self.single_use,
+            self.nonce,
+        )?;
+        if !self.inviter().verify(&terms, &self.signature) {
+            return Err(anyhow!("Invite has an invalid signature"));
+        }
+        if self.expires_at <= now {
+            return Err(anyhow!("Invite has expired"));
+        }
+        Ok(())
+    }
+}
+
+/// Everything the inviter vouches for by signing an invite.
+fn signed_bytes(
+    chat_id: ChatId,
+    inviter: PK,
+    role: Role,
+    expires_at: u64,
+    single_use: bool,
+    nonce: [u8; 16],
+) -> anyhow::Result<Vec<u8>> {
+    Ok(encode_cbor(&(
+        chat_id, inviter, role, expires_at, single_use, nonce,
+    ))?)
+}
+
+impl std::fmt::Display for InviteToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.as_bytes().map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", hex::encode(bytes))
+    }
+}
+
+impl FromStr for InviteToken {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim())?;
+        Ok(decode_cbor(bytes.as_slice())?)
+    }
+}
@@ -0,0 +1,219 @@
+#[cfg(test)]
+mod tests;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, anyhow};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use p2panda_core::Hash;
+use p2panda_core::cbor::{decode_cbor, encode_cbor};
+use serde::{Deserialize, Serialize};
+
+const KEYRING_VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+
+/// A passphrase used to protect everything the node stores on disk.
+#[derive(Clone, derive_more::From)]
+pub struct Passphrase(String);
+
+impl From<&str> for Passphrase {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Passphrase(***)")
+    }
+}
+
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+#[display("decryption failed")]
+pub struct DecryptionError;
+
+/// The symmetric key which encrypts the identity key file and the contents
+/// of the database. Each blob is also encrypted with a key of its own.
+#[derive(Clone)]
+pub struct DataKey(Arc<[u8; 32]>);
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DataKey(***)")
+    }
+}
+
+impl DataKey {
+    pub(crate) fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(Arc::new(bytes))
+    }
+
+    pub(crate) fn to_bytes(&self) -> [u8; 32] {
+        *self.0
+    }
+
+    pub(crate) fn random() -> Self {
+        Self::from_bytes(rand::random())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(self.0.as_slice()))
+    }
+
+    /// Encrypt with a random nonce, which is prepended to the ciphertext.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .expect("encryption into a Vec cannot fail");
+        [nonce.as_slice(), ciphertext.as_slice()].concat()
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(DecryptionError);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| DecryptionError)
+    }
+
+    /// A keyed hash of `data`, for looking things up without storing them in
+    /// the clear. The same `context` and `data` always give the same hash.
+    ///
+    /// BLAKE3 is not open to length extension, so hashing the key in front
+    /// of the input is enough to key it.
+    pub(crate) fn keyed_hash(&self, context: &str, data: &[u8]) -> [u8; 32] {
+        *Hash::new([self.0.as_slice(), context.as_bytes(), &[0], data].concat()).as_bytes()
+    }
+}
+
+/// Argon2id parameters, stored alongside the wrapped key so they can be
+/// raised for new keyrings without breaking old ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct KdfParams {
+    salt: [u8; 16],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl KdfParams {
+    pub(crate) fn new() -> Self {
+        Self {
+            salt: rand::random(),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+
+    pub(crate) fn derive(&self, passphrase: &Passphrase) -> anyhow::Result<DataKey> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| anyhow!("invalid key derivation parameters: {e}"))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.0.as_bytes(), &self.salt, &mut key)
+            .map_err(|e| anyhow!("key derivation failed: {e}"))?;
+        Ok(DataKey::from_bytes(key))
+    }
+}
+
+/// The on-disk form of the keyring.
+///
+/// Without a passphrase the data key is stored as-is, which still lets a
+/// passphrase be set later without re-encrypting any data.
+#[derive(Serialize, Deserialize)]
+struct KeyringFile {
+    version: u8,
+    kdf: Option<KdfParams>,
+    key: Vec<u8>,
+}
+
+/// Holds the unlocked [`DataKey`] and knows where its wrapped form is stored.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    path: PathBuf,
+    data_key: DataKey,
+}
+
+impl Keyring {
+    /// Unlock the keyring at `path`, or create a new one with a random data
+    /// key if none exists yet.
+    pub fn unlock_or_create(path: &Path, passphrase: Option<&Passphrase>) -> anyhow::Result<Self> {
+        if !path.exists() {
+            tracing::info!(?path, "creating new keyring");
+            let keyring = Self {
+                path: path.to_owned(),
+                data_key: DataKey::random(),
+            };
+            keyring.save(passphrase)?;
+            return Ok(keyring);
+        }
+
+        let file: KeyringFile =
+            decode_cbor(std::fs::read(path).context("read keyring")?.as_slice())?;
+        if file.version != KEYRING_VERSION {
+            return Err(anyhow!("unsupported keyring version {}", file.version));
+        }
+
+        let raw_key = match (&file.kdf, passphrase) {
+            (None, _) => file.key,
+            (Some(_), None) => return Err(anyhow!("a passphrase is required to unlock storage")),
+            (Some(kdf), Some(passphrase)) => kdf
+                .derive(passphrase)?
+                .open(&file.key)
+                .map_err(|_| anyhow!("wrong passphrase"))?,
+        };
+        let raw_key: [u8; 32] = raw_key
+            .try_into()
+            .map_err(|_| anyhow!("keyring is corrupt: {}", path.display()))?;
+
+        let keyring = Self {
+            path: path.to_owned(),
+            data_key: DataKey::from_bytes(raw_key),
+        };
+        if file.kdf.is_none() && passphrase.is_some() {
+            tracing::info!(?path, "protecting keyring with the configured passphrase");
+            keyring.save(passphrase)?;
+        }
+        Ok(keyring)
+    }
+
+    pub fn data_key(&self) -> &DataKey {
+        &self.data_key
+    }
+
+    /// Re-wrap the data key under a new passphrase, or store it unprotected
+    /// if `None`. Nothing that was encrypted with the data key changes.
+    pub fn change_passphrase(&self, passphrase: Option<&Passphrase>) -> anyhow::Result<()> {
+        self.save(passphrase)
+    }
+
+    fn save(&self, passphrase: Option<&Passphrase>) -> anyhow::Result<()> {
+        let file = match passphrase {
+            None => KeyringFile {
+                version: KEYRING_VERSION,
+                kdf: None,
+                key: self.data_key.0.to_vec(),
+            },
+            Some(passphrase) => {
+                let kdf = KdfParams::new();
+                let key = kdf.derive(passphrase)?.seal(self.data_key.0.as_slice());
+                KeyringFile {
+                    version: KEYRING_VERSION,
+                    kdf: Some(kdf),
+                    key,
+                }
+            }
+        };
+        crate::identity::write_secret(&self.path, &encode_cbor(&file)?)
+    }
+}
@@ -0,0 +1,39 @@
+use super::*;
+
+fn keyring_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!(
+            "dashchat-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ))
+        .join("keyring")
+}
+
+#[test]
+fn test_change_passphrase_keeps_data_key() {
+    let path = keyring_path();
+    let old = Passphrase::from("correct horse");
+    let new = Passphrase::from("battery staple");
+
+    let keyring = Keyring::unlock_or_create(&path, Some(&old)).unwrap();
+    let sealed = keyring.data_key().seal(b"secret");
+
+    assert!(Keyring::unlock_or_create(&path, None).is_err());
+    assert!(Keyring::unlock_or_create(&path, Some(&new)).is_err());
+
+    keyring.change_passphrase(Some(&new)).unwrap();
+
+    assert!(Keyring::unlock_or_create(&path, Some(&old)).is_err());
+    let keyring = Keyring::unlock_or_create(&path, Some(&new)).unwrap();
+    assert_eq!(keyring.data_key().open(&sealed).unwrap(), b"secret");
+
+    std::fs::remove_dir_all(path.parent().unwrap()).ok();
+}
+
+#[test]
+fn test_tampered_ciphertext_is_rejected() {
+    let key = DataKey::random();
+    let mut sealed = key.seal(b"secret");
+    *sealed.last_mut().unwrap() ^= 1;
+    assert!(key.open(&sealed).is_err());
+}
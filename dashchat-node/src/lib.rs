@@ -1,8 +1,13 @@
 #![feature(bool_to_result)]
 
+mod blobs;
 mod chat;
+mod db;
 mod forge;
 mod friend;
+mod identity;
+mod invite;
+mod keyring;
 mod network;
 mod node;
 mod operation;
@@ -17,13 +22,20 @@ pub mod testing;
 
 use p2panda_core::IdentityError;
 
-pub use chat::{ChatId, ChatMessage, ChatMessageContent};
-pub use node::{Node, NodeConfig, Notification};
+pub use blobs::{BlobProgress, MAX_BLOB_SIZE};
+pub use chat::{
+    AttachmentRef, ChatId, ChatMessage, ChatMessageContent, DeliveryState, DeliveryUpdate,
+    GroupInfo, GroupInfoUpdate, MessageCursor, MessageId, QuotedMessage, Role, SearchFilters,
+    SearchResult, Signal, SignalEvent, SnippetPart,
+};
+pub use invite::InviteToken;
+pub use keyring::Passphrase;
+pub use node::{Node, NodeConfig, Notification, StorageConfig};
 pub use operation::{InvitationMessage, Payload};
 pub use p2panda_core::PrivateKey;
 pub use p2panda_spaces::ActorId;
 use p2panda_spaces::OperationId;
-pub use spaces::MemberCode;
+pub use spaces::{ChatConditions, MemberCode};
 
 #[derive(
     Copy,
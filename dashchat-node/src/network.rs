@@ -72,6 +72,13 @@ impl<T: Eq + std::hash::Hash + std::fmt::Debug + Clone> AuthorStore<T> {
             });
     }
 
+    /// Forget every author of a topic, so that its logs are no longer synced.
+    pub async fn remove_topic(&self, topic: &T) {
+        if self.0.write().await.remove(topic).is_some() {
+            tracing::debug!(?topic, "removed authors");
+        }
+    }
+
     pub async fn authors(&self, topic: &T) -> Option<HashSet<PK>> {
         let authors = self.0.read().await;
         Some(
@@ -1,8 +1,17 @@
 mod author_operation;
+mod backup;
+mod blobs;
+mod group_info;
+mod invites;
+mod replay;
+mod retention;
+mod signals;
 mod stream_processing;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result, anyhow};
 use p2panda_auth::Access;
@@ -18,25 +27,32 @@ use p2panda_net::{
 use p2panda_spaces::OperationId;
 use p2panda_spaces::event::Event;
 use p2panda_spaces::member::Member;
-use p2panda_store::{LogStore, MemoryStore};
+use p2panda_store::{LogStore, MemoryStore, OperationStore};
 use p2panda_stream::{DecodeExt, IngestExt};
 use p2panda_sync::log_sync::LogSyncProtocol;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{RwLock, broadcast, mpsc};
 use tokio::task;
 use tokio_stream::{StreamExt, wrappers::ReceiverStream};
 use tracing::Instrument;
 
-use crate::chat::{Chat, ChatId};
-use crate::chat::{ChatMessage, ChatMessageContent};
+use crate::blobs::{BlobItem, BlobProgress, BlobStore};
+use crate::chat::{self, Chat, ChatId, MarkerKind, MessageCursor, Role, Timeline};
+use crate::chat::{
+    ChatMessage, ChatMessageContent, ChatMessagePayload, DeliveryUpdate, MessageId,
+    PAYLOAD_VERSION, QuotedMessage, SearchFilters, SearchResult, SignalEvent,
+};
+use crate::db::Database;
 use crate::forge::DashForge;
 use crate::friend::Friend;
+use crate::keyring::{Keyring, Passphrase};
 use crate::network::{AuthorStore, LogId, Topic};
 use crate::operation::{
     Extensions, InvitationMessage, Payload, decode_gossip_message, encode_gossip_message,
 };
-use crate::spaces::{DashManager, DashSpace, SpacesStore};
-use crate::store::OpStore;
-use crate::{AsBody, Cbor, PK, timestamp_now};
+use crate::spaces::{ChatConditions, DashManager, DashSpace, DashSpacesStore, SpacesStore};
+use crate::store::{OpStore, SqliteStore};
+use crate::util::ResultExt;
+use crate::{AsBody, Cbor, PK, ShortId, timestamp_now};
 
 pub use stream_processing::Notification;
 
@@ -46,47 +62,225 @@ const NETWORK_ID: [u8; 32] = [88; 32];
 
 const MAX_MESSAGE_SIZE: usize = 1000 * 10; // 10kb max. UDP payload size
 
+/// How long to collect read markers for a chat before publishing the latest.
+const MARKER_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to collect received markers for a chat before publishing the
+/// latest. Every marker is an operation in our log, so this bounds what
+/// acknowledging incoming messages costs: at most one operation per chat in
+/// this time, however many messages arrive, and none if a read marker
+/// covers them first.
+const RECEIVED_MARKER_DELAY: Duration = Duration::from_secs(3);
+
+/// How long a signal is shown after it was sent. Signals which should last
+/// longer, like typing, have to be sent again before they expire.
+const SIGNAL_TTL: Duration = Duration::from_secs(5);
+
+/// How often to look for messages which have outlived their chat's retention
+/// setting.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest we leave messages which have expired or been deleted in our
+/// own log before pruning it. Every pruning rewrites what is left of the
+/// log, so chats with a short retention setting prune every half lifetime
+/// rather than whenever anything expires.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many bytes of space messages a pruning operation carries, leaving
+/// room for the header in a gossip message. The rest follow in further
+/// operations.
+const MAX_PRUNE_PAYLOAD: usize = MAX_MESSAGE_SIZE / 2;
+
+/// How long to wait for the chunks of a blob we asked for before asking again.
+const BLOB_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many chunks of a blob to ask for at once.
+const BLOB_REQUEST_CHUNKS: usize = 16;
+/// How many requests in a row may go unanswered before a download is paused.
+const BLOB_IDLE_ROUNDS: usize = 5;
+/// The longest a node holding a blob waits before answering a request for it.
+/// Each holder waits a random time and leaves out whatever another holder
+/// sent in the meantime, so that a request is mostly answered only once.
+const BLOB_ANSWER_DELAY: Duration = Duration::from_millis(250);
+
+const KEYRING_FILE: &str = "keyring";
+const PRIVATE_KEY_FILE: &str = "private_key";
+const DATABASE_FILE: &str = "dashchat.sqlite";
+
+/// Chats we have joined, restored when the node starts.
+const KV_CHATS: &str = "node/chats";
+/// Friends whose inboxes we subscribe to, restored when the node starts.
+const KV_FRIENDS: &str = "node/friends";
+/// Chats we have left or been removed from, with when.
+const KV_LEFT_CHATS: &str = "node/left_chats";
+/// Invites we have let someone join with, by nonce and who joined.
+const KV_REDEEMED_INVITES: &str = "node/redeemed_invites";
+
 #[derive(Clone, Debug)]
-pub struct NodeConfig {}
+pub struct NodeConfig {
+    pub storage: StorageConfig,
+    /// Protects the identity key and database. Only used with a data
+    /// directory.
+    pub passphrase: Option<Passphrase>,
+}
 
 impl Default for NodeConfig {
     fn default() -> Self {
-        Self {}
+        Self {
+            storage: StorageConfig::Memory,
+            passphrase: None,
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Persist all node state, including the identity key, in `data_dir`.
+    pub fn with_data_dir(data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            storage: StorageConfig::DataDir(data_dir.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_passphrase(mut self, passphrase: impl Into<Passphrase>) -> Self {
+        self.passphrase = Some(passphrase.into());
+        self
     }
+
+    fn data_dir(&self) -> Option<&Path> {
+        match &self.storage {
+            StorageConfig::Memory => None,
+            StorageConfig::DataDir(dir) => Some(dir),
+        }
+    }
+
+    /// Unlock the keyring in the data directory, creating it on first run.
+    fn unlock(&self) -> anyhow::Result<Option<Keyring>> {
+        self.data_dir()
+            .map(|dir| {
+                Keyring::unlock_or_create(&dir.join(KEYRING_FILE), self.passphrase.as_ref())
+                    .context("unlock keyring")
+            })
+            .transpose()
+    }
+}
+
+/// Where the node keeps its identity, operations and spaces state.
+#[derive(Clone, Debug)]
+pub enum StorageConfig {
+    /// Everything is lost when the node shuts down.
+    Memory,
+    /// Persist everything in this directory.
+    DataDir(PathBuf),
 }
 
 #[derive(Clone, Debug)]
 pub struct Node {
     pub(crate) op_store: OpStore,
+    /// Where local node state is persisted, if anywhere.
+    db: Option<Database>,
+    keyring: Option<Keyring>,
     pub network: Network<Topic>,
     chats: Arc<RwLock<HashMap<ChatId, Chat>>>,
+    /// When we last left each chat we have left, so that invitations from
+    /// before then are ignored.
+    left_chats: Arc<RwLock<HashMap<ChatId, u64>>>,
+    /// Our invites which have let someone join, so that single-use ones are
+    /// used up and nobody is added twice by replaying their request.
+    redeemed_invites: Arc<RwLock<HashSet<([u8; 16], PK)>>>,
+    /// The task processing each topic's stream of operations.
+    stream_tasks: Arc<RwLock<HashMap<Topic, task::AbortHandle>>>,
+    /// The timeline of each chat, which can be read without locking `chats`.
+    timelines: Arc<RwLock<HashMap<ChatId, Timeline>>>,
     author_store: AuthorStore<Topic>,
     /// Used solely to extract the keybundle
     spaces_store: SpacesStore,
-    manager: DashManager,
+    pub(crate) manager: DashManager,
     /// mapping from space operations to header hashes, so that dependencies
     /// can be declared
     space_dependencies: Arc<RwLock<HashMap<OperationId, p2panda_core::Hash>>>,
-    _config: NodeConfig,
+    config: NodeConfig,
     private_key: PrivateKey,
     friends: Arc<RwLock<HashMap<PK, Friend>>>,
+    /// Markers waiting to be published, at most one of each kind per chat.
+    pending_markers: Arc<RwLock<HashMap<(ChatId, MarkerKind), MessageId>>>,
     notification_tx: Option<mpsc::Sender<Notification>>,
+    delivery_tx: broadcast::Sender<DeliveryUpdate>,
+    signal_tx: broadcast::Sender<SignalEvent>,
+    group_info_tx: broadcast::Sender<chat::GroupInfoUpdate>,
+    blobs: BlobStore,
+    /// Downloads in progress, woken whenever a piece of their blob arrives.
+    downloads: Arc<RwLock<HashMap<p2panda_core::Hash, Arc<tokio::sync::Notify>>>>,
+    /// What we are about to send of each blob in answer to requests, unless
+    /// another holder sends it first.
+    blob_answers: Arc<RwLock<HashMap<p2panda_core::Hash, HashSet<BlobItem>>>>,
+    blob_progress_tx: broadcast::Sender<BlobProgress>,
     // // XXX: temporary hack
     // ooo_buffer: Arc<RwLock<Vec<Operation<Extensions>>>>,
 }
 
 impl Node {
-    #[tracing::instrument(skip_all, fields(me = ?PK::from(private_key.public_key())))]
+    /// Start a node with the identity stored in the configured data directory.
+    /// Without a data directory a fresh identity is generated every time.
+    pub async fn open(
+        config: NodeConfig,
+        notification_tx: Option<mpsc::Sender<Notification>>,
+    ) -> Result<Self> {
+        let keyring = config.unlock()?;
+        let private_key = match config.data_dir().zip(keyring.as_ref()) {
+            Some((dir, keyring)) => crate::identity::load_or_create_private_key(
+                &dir.join(PRIVATE_KEY_FILE),
+                keyring.data_key(),
+            )?,
+            None => PrivateKey::new(),
+        };
+        Self::start(private_key, config, keyring, notification_tx).await
+    }
+
+    /// Start a node with the given identity, keeping everything in memory.
+    ///
+    /// A data directory holds an identity of its own, which its spaces and
+    /// key state belong to, so nodes with one are only started with
+    /// [`Node::open`].
     pub async fn new(
         private_key: PrivateKey,
-        _config: NodeConfig,
+        config: NodeConfig,
+        notification_tx: Option<mpsc::Sender<Notification>>,
+    ) -> Result<Self> {
+        if config.data_dir().is_some() {
+            return Err(anyhow!(
+                "Node::new can't use a data directory, open it with Node::open"
+            ));
+        }
+        Self::start(private_key, config, None, notification_tx).await
+    }
+
+    #[tracing::instrument(skip_all, fields(me = ?PK::from(private_key.public_key())))]
+    async fn start(
+        private_key: PrivateKey,
+        config: NodeConfig,
+        keyring: Option<Keyring>,
         notification_tx: Option<mpsc::Sender<Notification>>,
     ) -> Result<Self> {
         let public_key = PK::from(private_key.public_key());
 
         let mdns = LocalDiscovery::new();
 
-        let op_store = MemoryStore::<LogId, Extensions>::new();
+        let db = match config.data_dir().zip(keyring.as_ref()) {
+            Some((dir, keyring)) => Some(
+                Database::open(dir.join(DATABASE_FILE), keyring.data_key().clone())
+                    .context("open database")?,
+            ),
+            None => None,
+        };
+
+        let op_store = match &db {
+            None => OpStore::from(MemoryStore::<LogId, Extensions>::new()),
+            Some(db) => OpStore::from(SqliteStore::new(db.clone())),
+        };
+        let blobs = match &db {
+            None => BlobStore::memory(),
+            Some(db) => BlobStore::Sqlite(db.clone()),
+        };
         let author_store = AuthorStore::new();
 
         // TODO: unnecessary
@@ -147,8 +341,11 @@ impl Node {
         let network = network_builder.build().await.context("spawn p2p network")?;
         let chats = Arc::new(RwLock::new(HashMap::new()));
 
-        let spaces_store: SpacesStore =
-            crate::spaces::create_test_store(private_key.clone()).into();
+        let spaces_store: SpacesStore = match db.clone() {
+            None => DashSpacesStore::memory(),
+            Some(db) => DashSpacesStore::load(db).context("load spaces store")?,
+        }
+        .into();
 
         let rng = Rng::default();
 
@@ -159,29 +356,112 @@ impl Node {
         let manager = DashManager::new(spaces_store.clone(), forge, rng).unwrap();
 
         let node = Self {
-            op_store: OpStore::from(op_store),
+            op_store,
+            db,
+            keyring,
             author_store,
             spaces_store,
             network,
             chats,
+            left_chats: Arc::new(RwLock::new(HashMap::new())),
+            redeemed_invites: Arc::new(RwLock::new(HashSet::new())),
+            stream_tasks: Arc::new(RwLock::new(HashMap::new())),
+            timelines: Arc::new(RwLock::new(HashMap::new())),
             manager: manager.clone(),
             space_dependencies: Arc::new(RwLock::new(HashMap::new())),
-            _config,
+            config,
             private_key,
             friends: Arc::new(RwLock::new(HashMap::new())),
+            pending_markers: Arc::new(RwLock::new(HashMap::new())),
             notification_tx,
+            delivery_tx: broadcast::channel(256).0,
+            signal_tx: broadcast::channel(256).0,
+            group_info_tx: broadcast::channel(256).0,
+            blobs,
+            downloads: Arc::new(RwLock::new(HashMap::new())),
+            blob_answers: Arc::new(RwLock::new(HashMap::new())),
+            blob_progress_tx: broadcast::channel(256).0,
         };
 
         // TODO: this doesn't seem to make a difference
         manager.register_member(&node.me().await?).await?;
 
+        node.restore_authors(public_key.into()).await?;
         node.initialize_inbox(public_key).await?;
 
-        // TODO: locally store list of groups and initialize them when the node starts
+        node.restore().await?;
+        node.spawn_retention_loop();
 
         Ok(node)
     }
 
+    /// Resubscribe to the inboxes of all stored friends and to all stored chats.
+    async fn restore(&self) -> anyhow::Result<()> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+
+        for (public_key, ()) in db.list::<PK, ()>(KV_FRIENDS)? {
+            tracing::debug!(?public_key, "restoring friend");
+            self.restore_authors(public_key.into()).await?;
+            let network_tx = self.initialize_inbox(public_key).await?;
+            self.friends
+                .write()
+                .await
+                .insert(public_key, Friend { network_tx });
+        }
+
+        self.left_chats
+            .write()
+            .await
+            .extend(db.list::<ChatId, u64>(KV_LEFT_CHATS)?);
+        self.redeemed_invites.write().await.extend(
+            db.list::<([u8; 16], PK), ()>(KV_REDEEMED_INVITES)?
+                .into_iter()
+                .map(|(key, ())| key),
+        );
+
+        let chat_ids = db.list::<ChatId, ()>(KV_CHATS)?;
+        for (chat_id, ()) in chat_ids.iter() {
+            tracing::debug!(?chat_id, "restoring chat");
+            self.restore_authors((*chat_id).into()).await?;
+            self.initialize_group(*chat_id).await?;
+        }
+
+        let inboxes = self
+            .friends
+            .read()
+            .await
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let topics = [self.public_key()]
+            .into_iter()
+            .chain(inboxes)
+            .map(Topic::Inbox)
+            .chain(
+                chat_ids
+                    .into_iter()
+                    .map(|(chat_id, ())| Topic::Chat(chat_id)),
+            );
+        for topic in topics {
+            self.replay_topic(topic).await?;
+        }
+
+        self.resume_downloads().await?;
+
+        Ok(())
+    }
+
+    /// Register every author we hold a log from, so that sync offers those
+    /// logs again after a restart.
+    async fn restore_authors(&self, topic: Topic) -> anyhow::Result<()> {
+        for (public_key, _) in self.op_store.get_log_heights(&topic).await? {
+            self.author_store.add_author(topic, public_key).await;
+        }
+        Ok(())
+    }
+
     pub async fn me(&self) -> anyhow::Result<p2panda_spaces::member::Member> {
         let long_term_key_bundle = self.spaces_store.long_term_key_bundle().await?;
         Ok(p2panda_spaces::member::Member::new(
@@ -190,6 +470,17 @@ impl Node {
         ))
     }
 
+    /// Protect the data directory with a new passphrase, or remove the
+    /// passphrase with `None`. Stored data is not re-encrypted, so nothing
+    /// is lost.
+    pub fn change_passphrase(&self, passphrase: Option<Passphrase>) -> anyhow::Result<()> {
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or_else(|| anyhow!("Node has no data directory"))?;
+        keyring.change_passphrase(passphrase.as_ref())
+    }
+
     /// Create a new chat Space, and subscribe to the Topic for this chat.
     #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
     pub async fn create_group(&self) -> anyhow::Result<(ChatId, Chat)> {
@@ -200,7 +491,7 @@ impl Node {
             .manager
             .create_space(
                 chat_id,
-                &[(self.private_key.public_key().into(), Access::manage())],
+                &[(self.private_key.public_key().into(), Role::Admin.access())],
             )
             .await?;
 
@@ -221,20 +512,44 @@ impl Node {
         Ok(chat)
     }
 
+    /// Remove ourselves from a chat's Space and stop following the chat.
+    ///
+    /// Invitations to the chat sent before now are ignored from then on, but
+    /// we can be invited again.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn leave_group(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        let msgs = self
+            .manager
+            .space(chat_id)
+            .await?
+            .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?
+            .remove(self.public_key().into())
+            .await?;
+
+        self.author_operation(chat_id.into(), Payload::SpaceControl(msgs))
+            .await?;
+
+        self.close_group(chat_id).await
+    }
+
     pub async fn get_groups(&self) -> anyhow::Result<Vec<ChatId>> {
         let groups = self.chats.read().await.keys().cloned().collect();
         Ok(groups)
     }
 
+    /// Add a member to a chat with the given role, and invite them in their
+    /// inbox.
     #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
-    pub async fn add_member(&self, chat_id: ChatId, pubkey: PK) -> anyhow::Result<()> {
+    pub async fn add_member(&self, chat_id: ChatId, pubkey: PK, role: Role) -> anyhow::Result<()> {
+        if !self.own_role(chat_id).await?.may_add(role) {
+            return Err(anyhow!("Not allowed to add members as {role:?}"));
+        }
         let msgs = self
             .manager
             .space(chat_id)
             .await?
             .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?
-            // TODO: we need an access level for only adding but not removing members
-            .add(pubkey.into(), Access::manage())
+            .add(pubkey.into(), role.access())
             .await?;
 
         self.author_operation(
@@ -249,10 +564,91 @@ impl Node {
         Ok(())
     }
 
+    /// Remove a member from a chat, and tell them in their inbox.
+    ///
+    /// Removing them from the space replaces the group secret with a new one
+    /// which only the remaining members receive, so the removed member can't
+    /// read anything sent from then on.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn remove_member(&self, chat_id: ChatId, pubkey: PK) -> anyhow::Result<()> {
+        if pubkey == self.public_key() {
+            return Err(anyhow!("Can't remove yourself from a chat"));
+        }
+        if !self.own_role(chat_id).await?.may_manage() {
+            return Err(anyhow!("Only admins can remove members"));
+        }
+        let msgs = self
+            .manager
+            .space(chat_id)
+            .await?
+            .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?
+            .remove(pubkey.into())
+            .await?;
+
+        self.author_operation(chat_id.into(), Payload::SpaceControl(msgs))
+            .await?;
+
+        self.author_operation(
+            pubkey.into(),
+            Payload::Invitation(InvitationMessage::RemovedFromGroup(chat_id)),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Change the role of a member of a chat.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn set_role(&self, chat_id: ChatId, pubkey: PK, role: Role) -> anyhow::Result<()> {
+        if !self.own_role(chat_id).await?.may_manage() {
+            return Err(anyhow!("Only admins can change roles"));
+        }
+        let current = self
+            .get_role(chat_id, pubkey)
+            .await?
+            .ok_or_else(|| anyhow!("Not a member of {chat_id}: {pubkey}"))?;
+        if current == role {
+            return Ok(());
+        }
+
+        let space = self
+            .manager
+            .space(chat_id)
+            .await?
+            .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?;
+        let msgs = if role > current {
+            space.promote(pubkey.into(), role.access()).await?
+        } else {
+            space.demote(pubkey.into(), role.access()).await?
+        };
+
+        self.author_operation(chat_id.into(), Payload::SpaceControl(msgs))
+            .await?;
+
+        Ok(())
+    }
+
+    /// The role of a member of a chat, or `None` if they aren't a member.
+    pub async fn get_role(&self, chat_id: ChatId, pubkey: PK) -> anyhow::Result<Option<Role>> {
+        let member = p2panda_spaces::ActorId::from(pubkey);
+        Ok(self
+            .get_members(chat_id)
+            .await?
+            .iter()
+            .find(|(id, _)| *id == member)
+            .map(|(_, access)| Role::from_access(access)))
+    }
+
+    async fn own_role(&self, chat_id: ChatId) -> anyhow::Result<Role> {
+        self.get_role(chat_id, self.public_key())
+            .await?
+            .ok_or_else(|| anyhow!("Not a member of {chat_id}"))
+    }
+
     pub async fn get_members(
         &self,
         chat_id: ChatId,
-    ) -> anyhow::Result<Vec<(p2panda_spaces::ActorId, Access)>> {
+    ) -> anyhow::Result<Vec<(p2panda_spaces::ActorId, Access<ChatConditions>)>> {
         if let Some(space) = self.manager.space(chat_id).await? {
             Ok(space.members().await?)
         } else {
@@ -263,17 +659,106 @@ impl Node {
 
     #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
     pub async fn get_messages(&self, chat_id: ChatId) -> anyhow::Result<Vec<ChatMessage>> {
+        self.get_messages_page(chat_id, MessageCursor::Latest, usize::MAX)
+            .await
+    }
+
+    /// Up to `limit` messages from `cursor` on, in timeline order.
+    ///
+    /// This reads from an index of its own, so it doesn't wait for the chat
+    /// to finish processing incoming operations.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn get_messages_page(
+        &self,
+        chat_id: ChatId,
+        cursor: MessageCursor,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let timeline = self
+            .timelines
+            .read()
+            .await
+            .get(&chat_id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        let members = self.other_member_count(chat_id).await?;
+
+        timeline
+            .page(cursor, limit, members)
+            .ok_or_else(|| anyhow!("Message not found: {cursor:?}"))
+    }
+
+    /// The `limit` most recent messages, in timeline order.
+    pub async fn get_latest_messages(
+        &self,
+        chat_id: ChatId,
+        limit: usize,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        self.get_messages_page(chat_id, MessageCursor::Latest, limit)
+            .await
+    }
+
+    /// Search the text of messages in every chat, or in the one chat given in
+    /// `filters`, for messages containing all words of `query`.
+    ///
+    /// Results are newest first. Only messages as currently shown are
+    /// searched: edits replace the text they edit, and deleted messages are
+    /// gone.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn search(
+        &self,
+        query: &str,
+        filters: SearchFilters,
+    ) -> anyhow::Result<Vec<SearchResult>> {
+        let terms: Vec<String> = chat::words(query).collect();
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let timelines: Vec<(ChatId, Timeline)> = {
+            let timelines = self.timelines.read().await;
+            match filters.chat_id {
+                Some(chat_id) => vec![(
+                    chat_id,
+                    timelines
+                        .get(&chat_id)
+                        .cloned()
+                        .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?,
+                )],
+                None => timelines
+                    .iter()
+                    .map(|(chat_id, timeline)| (*chat_id, timeline.clone()))
+                    .collect(),
+            }
+        };
+
+        let mut results: Vec<SearchResult> = timelines
+            .into_iter()
+            .flat_map(|(chat_id, timeline)| {
+                timeline
+                    .search(&terms, &filters)
+                    .into_iter()
+                    .map(move |message| SearchResult::new(chat_id, &message, &terms))
+            })
+            .collect();
+        results.sort_by(|a, b| (b.timestamp, b.message_id).cmp(&(a.timestamp, a.message_id)));
+        Ok(results)
+    }
+
+    /// A message along with all replies to it, and replies to those replies.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn get_thread(
+        &self,
+        chat_id: ChatId,
+        root: MessageId,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
         let chats = self.chats.read().await;
         let chat = chats
             .get(&chat_id)
             .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
 
-        let mut msgs: Vec<ChatMessage> = chat.messages.iter().cloned().collect();
-        let original = msgs.clone();
-        msgs.sort();
-        assert_eq!(msgs, original);
-
-        Ok(msgs)
+        chat.thread(&root)
+            .ok_or_else(|| anyhow!("Message not found: {root}"))
     }
 
     #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
@@ -288,23 +773,383 @@ impl Node {
             .await?
             .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?;
 
+        let role = self.own_role(chat_id).await?;
+        if !role.may_send(&message) {
+            return Err(anyhow!("Not allowed to send this message as {role:?}"));
+        }
+
         // NOTE: duplication of timestamp and author
-        let message = ChatMessage {
+        let payload = ChatMessagePayload {
+            version: PAYLOAD_VERSION,
             content: message,
             author: self.public_key(),
             timestamp: timestamp_now(),
         };
-        let encrypted = space.publish(&encode_cbor(&message.clone())?).await?;
+        let encrypted = space.publish(&encode_cbor(&payload)?).await?;
+        let message = ChatMessage::new(encrypted.hash.into(), self.public_key(), payload);
 
         let topic = chat_id.into();
 
+        self.set_pending(chat_id, message.id, true).await;
         let _header = self
             .author_operation(topic, Payload::SpaceControl(vec![encrypted]))
             .await?;
+        self.set_pending(chat_id, message.id, false).await;
 
+        let members = self.other_member_count(chat_id).await?;
+        if let Some(chat) = self.chats.read().await.get(&chat_id) {
+            self.emit_delivery(chat, [message.id], members);
+        }
+
+        Ok(message)
+    }
+
+    /// Send a message in reply to an earlier one in the same chat.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn send_reply(
+        &self,
+        chat_id: ChatId,
+        reply_to: MessageId,
+        text: String,
+    ) -> anyhow::Result<ChatMessage> {
+        let quoted = {
+            let chats = self.chats.read().await;
+            let chat = chats
+                .get(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            chat.message(&reply_to)
+                .map(QuotedMessage::new)
+                .ok_or_else(|| anyhow!("Message not found: {reply_to}"))?
+        };
+
+        let mut message = self
+            .send_message(chat_id, ChatMessageContent::Reply { reply_to, text })
+            .await?;
+        message.quoted = Some(quoted);
         Ok(message)
     }
 
+    /// Replace the text of one of our own earlier messages.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn edit_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> anyhow::Result<ChatMessage> {
+        let revision = {
+            let chats = self.chats.read().await;
+            let chat = chats
+                .get(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            let history = chat
+                .edit_history(&message_id)
+                .ok_or_else(|| anyhow!("Message not found: {message_id}"))?;
+            if history[0].author != self.public_key() {
+                return Err(anyhow!("Only the author can edit a message"));
+            }
+            history.len() as u64
+        };
+
+        self.send_message(
+            chat_id,
+            ChatMessageContent::Edit {
+                target: message_id,
+                text,
+                revision,
+            },
+        )
+        .await
+    }
+
+    /// A message as originally sent, followed by every edit made to it.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn get_edit_history(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> anyhow::Result<Vec<ChatMessage>> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+
+        chat.edit_history(&message_id)
+            .ok_or_else(|| anyhow!("Message not found: {message_id}"))
+    }
+
+    /// Delete a message for everyone. Only our own messages can be deleted,
+    /// unless moderation is on in the chat and we are an admin.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> anyhow::Result<()> {
+        let (author, moderation) = {
+            let chats = self.chats.read().await;
+            let chat = chats
+                .get(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            let author = chat
+                .message(&message_id)
+                .ok_or_else(|| anyhow!("Message not found: {message_id}"))?
+                .author;
+            (author, chat.moderation())
+        };
+        let moderation = if author == self.public_key() {
+            None
+        } else {
+            match moderation {
+                Some((setting, true)) if self.own_role(chat_id).await? == Role::Admin => {
+                    Some(setting)
+                }
+                _ => return Err(anyhow!("Not allowed to delete someone else's message")),
+            }
+        };
+
+        self.send_message(
+            chat_id,
+            ChatMessageContent::Delete {
+                target: message_id,
+                moderation,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Let the admins of a chat delete anyone's messages, or only their own.
+    ///
+    /// The setting is published to the chat, so that every member agrees on
+    /// it. It applies to deletions made after it, and only admins can change
+    /// it.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn set_moderation(&self, chat_id: ChatId, enabled: bool) -> anyhow::Result<()> {
+        self.send_message(chat_id, ChatMessageContent::Moderation { enabled })
+            .await?;
+        Ok(())
+    }
+
+    /// Whether admins can delete anyone's messages in a chat.
+    pub async fn get_moderation(&self, chat_id: ChatId) -> anyhow::Result<bool> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        Ok(chat.moderation().is_some_and(|(_, enabled)| enabled))
+    }
+
+    /// Remove the body of the operation which carried a chat message, so that
+    /// its ciphertext no longer exists on this node.
+    async fn purge_message(&self, message_id: MessageId) -> anyhow::Result<()> {
+        let op_id = OperationId::from(p2panda_core::Hash::from(message_id));
+        self.spaces_store.write().await.remove_message(&op_id)?;
+        let Some(hash) = self.space_dependencies.read().await.get(&op_id).copied() else {
+            tracing::warn!(?message_id, "no operation found for deleted message");
+            return Ok(());
+        };
+
+        let mut op_store = self.op_store.clone();
+        let Some((_, Some(body))) = op_store.get_operation(hash).await? else {
+            return Ok(());
+        };
+        // Only purge operations which carry nothing but this message
+        match Payload::try_from_body(body)? {
+            Payload::SpaceControl(msgs) if msgs.len() == 1 => {
+                op_store.delete_payload(hash).await?;
+                tracing::debug!(?message_id, hash = hash.short(), "purged message payload");
+            }
+            _ => {
+                tracing::warn!(?message_id, "not purging operation with other payloads");
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark everything up to a message as read.
+    ///
+    /// Markers are collected for a short while and only the latest one is
+    /// published, so reading many messages at once costs a single operation.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn mark_read(&self, chat_id: ChatId, message_id: MessageId) -> anyhow::Result<()> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        if chat.message(&message_id).is_none() {
+            return Err(anyhow!("Message not found: {message_id}"));
+        }
+        self.schedule_marker(chat, MarkerKind::Read, message_id)
+            .await;
+        Ok(())
+    }
+
+    /// Publish a marker of our position in a chat after [`MARKER_DELAY`], or
+    /// [`RECEIVED_MARKER_DELAY`] for received markers, unless we have
+    /// already marked that far.
+    ///
+    /// Only the latest marker of each kind scheduled in the meantime is
+    /// published. Reading a message implies having received it, so a read
+    /// marker also stands in for received markers up to the same message.
+    async fn schedule_marker(&self, chat: &Chat, kind: MarkerKind, up_to: MessageId) {
+        let Some(position) = chat.position(&up_to) else {
+            return;
+        };
+        let chat_id = chat.id;
+        let previous = {
+            let mut pending = self.pending_markers.write().await;
+            let latest = pending
+                .get(&(chat_id, kind))
+                .and_then(|id| chat.position(id))
+                .or_else(|| chat.marker_position(kind, self.public_key()));
+            if latest.is_some_and(|latest| latest >= position) {
+                return;
+            }
+            pending.insert((chat_id, kind), up_to)
+        };
+        if previous.is_some() {
+            return;
+        }
+
+        let delay = match kind {
+            MarkerKind::Read => MARKER_DELAY,
+            MarkerKind::Received => RECEIVED_MARKER_DELAY,
+        };
+        let node = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let (up_to, read) = {
+                let mut pending = node.pending_markers.write().await;
+                let Some(up_to) = pending.remove(&(chat_id, kind)) else {
+                    return;
+                };
+                (up_to, pending.get(&(chat_id, MarkerKind::Read)).copied())
+            };
+            // A marker we published in the meantime, e.g. while replaying our
+            // own history, or a read marker about to be, may already cover
+            // this one.
+            let superseded = node.chats.read().await.get(&chat_id).is_none_or(|chat| {
+                let position = chat.position(&up_to);
+                let read = read
+                    .filter(|_| kind == MarkerKind::Received)
+                    .and_then(|id| chat.position(&id));
+                position <= chat.marker_position(kind, node.public_key()) || position <= read
+            });
+            if superseded {
+                return;
+            }
+            node.send_message(chat_id, kind.content(up_to))
+                .await
+                .ok_or_warn("failed to publish marker");
+        });
+    }
+
+    /// Receive a [`DeliveryUpdate`] whenever one of our messages gets further
+    /// to the other members of its chat.
+    pub fn subscribe_delivery(&self) -> broadcast::Receiver<DeliveryUpdate> {
+        self.delivery_tx.subscribe()
+    }
+
+    fn emit_delivery(
+        &self,
+        chat: &Chat,
+        message_ids: impl IntoIterator<Item = MessageId>,
+        members: usize,
+    ) {
+        for message_id in message_ids {
+            if let Some(state) = chat.delivery_state(&message_id, members) {
+                // Nobody listening is fine.
+                self.delivery_tx
+                    .send(DeliveryUpdate {
+                        chat_id: chat.id,
+                        message_id,
+                        state,
+                    })
+                    .ok();
+            }
+        }
+    }
+
+    async fn set_pending(&self, chat_id: ChatId, message_id: MessageId, pending: bool) {
+        if let Some(chat) = self.chats.write().await.get_mut(&chat_id) {
+            chat.set_pending(message_id, pending);
+        }
+    }
+
+    /// The number of members of a chat other than ourselves.
+    async fn other_member_count(&self, chat_id: ChatId) -> anyhow::Result<usize> {
+        Ok(self.get_members(chat_id).await?.len().saturating_sub(1))
+    }
+
+    /// The members who have read a message.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn get_read_by(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> anyhow::Result<Vec<PK>> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+
+        chat.marked_by(MarkerKind::Read, &message_id)
+            .ok_or_else(|| anyhow!("Message not found: {message_id}"))
+    }
+
+    /// React to a message with an emoji.
+    pub async fn react(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        emoji: String,
+    ) -> anyhow::Result<()> {
+        self.send_reaction(chat_id, message_id, emoji, false).await
+    }
+
+    /// Take back an earlier reaction to a message.
+    pub async fn unreact(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        emoji: String,
+    ) -> anyhow::Result<()> {
+        self.send_reaction(chat_id, message_id, emoji, true).await
+    }
+
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    async fn send_reaction(
+        &self,
+        chat_id: ChatId,
+        target: MessageId,
+        emoji: String,
+        remove: bool,
+    ) -> anyhow::Result<()> {
+        let clock = {
+            let chats = self.chats.read().await;
+            let chat = chats
+                .get(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            if chat.message(&target).is_none() {
+                return Err(anyhow!("Message not found: {target}"));
+            }
+            chat.next_reaction_clock(&target, &emoji, self.public_key())
+        };
+
+        self.send_message(
+            chat_id,
+            ChatMessageContent::Reaction {
+                target,
+                emoji,
+                remove,
+                clock,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
     pub fn public_key(&self) -> PK {
         self.private_key.public_key().into()
     }
@@ -330,6 +1175,9 @@ impl Node {
             .await?;
 
         // Store the friend
+        if let Some(db) = &self.db {
+            db.put(KV_FRIENDS, &public_key, &())?;
+        }
         self.friends.write().await.insert(
             public_key.clone(),
             Friend {
@@ -355,6 +1203,9 @@ impl Node {
     #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
     pub async fn remove_friend(&self, public_key: PK) -> anyhow::Result<()> {
         // TODO: shutdown inbox task, etc.
+        if let Some(db) = &self.db {
+            db.remove(KV_FRIENDS, &public_key)?;
+        }
         self.friends.write().await.remove(&public_key);
         Ok(())
     }
@@ -7,16 +7,28 @@ use crate::{polestar as p, timestamp_now};
 
 use super::*;
 
-pub type OpStore = p2panda_store::MemoryStore<LogId, Extensions>;
-
 impl Node {
     #[tracing::instrument(skip_all)]
-    pub(super) async fn author_operation(
+    pub(crate) async fn author_operation(
+        &self,
+        topic: Topic,
+        payload: Payload,
+    ) -> Result<Header<Extensions>, anyhow::Error> {
+        self.author_operation_with_deps(topic, payload, vec![], false)
+            .await
+    }
+
+    /// Author an operation which replaces everything before it in our log on
+    /// the topic, so `payload` has to carry whatever of the log is still
+    /// needed. Every node deletes the earlier operations once it has this
+    /// one.
+    #[tracing::instrument(skip_all)]
+    pub(super) async fn author_pruning_operation(
         &self,
         topic: Topic,
         payload: Payload,
     ) -> Result<Header<Extensions>, anyhow::Error> {
-        self.author_operation_with_deps(topic, payload, vec![])
+        self.author_operation_with_deps(topic, payload, vec![], true)
             .await
     }
 
@@ -26,6 +38,7 @@ impl Node {
         topic: Topic,
         payload: Payload,
         mut deps: Vec<p2panda_core::Hash>,
+        prune: bool,
     ) -> Result<Header<Extensions>, anyhow::Error> {
         let mut sd = self.space_dependencies.write().await;
         let (ids, space_deps): (Vec<OperationId>, Vec<Hash>) = match &payload {
@@ -73,6 +86,7 @@ impl Node {
             topic.clone(),
             payload.clone(),
             deps,
+            prune,
         )
         .await?;
 
@@ -99,12 +113,12 @@ impl Node {
         }
 
         let result = p2panda_stream::operation::ingest_operation(
-            &mut *self.op_store.clone(),
+            &mut self.op_store.clone(),
             header.clone(),
             body.clone(),
             header.to_bytes(),
             &topic,
-            false,
+            prune,
         )
         .await?;
 
@@ -184,6 +198,7 @@ pub(crate) async fn create_operation(
     topic: Topic,
     payload: Payload,
     deps: Vec<p2panda_core::Hash>,
+    prune: bool,
 ) -> Result<Operation<Extensions>, anyhow::Error> {
     let public_key = private_key.public_key();
     let log_id = topic.clone();
@@ -192,6 +207,7 @@ pub(crate) async fn create_operation(
 
     let extensions = Extensions {
         log_id: log_id.clone(),
+        prune,
     };
 
     // TODO: atomicity, see https://github.com/p2panda/p2panda/issues/798
@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use p2panda_core::Body;
+use p2panda_core::cbor::decode_cbor;
+use p2panda_store::OperationStore;
+use serde::{Deserialize, Serialize};
+
+use crate::keyring::KdfParams;
+
+use super::*;
+
+const BACKUP_VERSION: u8 = 1;
+
+/// The on-disk form of a backup. Everything except the key derivation
+/// parameters is encrypted with a key derived from the backup passphrase.
+#[derive(Serialize, Deserialize)]
+struct BackupFile {
+    version: u8,
+    kdf: KdfParams,
+    contents: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupContents {
+    private_key: [u8; 32],
+    /// Every key/value entry: spaces key material and state, friends and
+    /// chats.
+    entries: Vec<(String, Vec<u8>, Vec<u8>)>,
+    /// Every operation as its signed header bytes and body bytes.
+    operations: Vec<(Topic, Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Node {
+    /// Write an archive of this node's identity and all of its persisted state
+    /// to `path`, encrypted with `passphrase`.
+    ///
+    /// Only nodes with a data directory can be backed up.
+    pub async fn export_backup(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: &Passphrase,
+    ) -> anyhow::Result<()> {
+        let db = self
+            .db
+            .as_ref()
+            .ok_or_else(|| anyhow!("only nodes with a data directory can be backed up"))?;
+
+        let operations = self
+            .op_store
+            .operations(std::iter::empty())?
+            .into_iter()
+            .map(|(_, topic, header, body)| (topic, header.to_bytes(), body.map(|b| b.to_bytes())))
+            .collect();
+        let contents = BackupContents {
+            private_key: *self.private_key.as_bytes(),
+            entries: db.entries()?,
+            operations,
+        };
+
+        let kdf = KdfParams::new();
+        let contents = kdf.derive(passphrase)?.seal(&encode_cbor(&contents)?);
+        let file = BackupFile {
+            version: BACKUP_VERSION,
+            kdf,
+            contents,
+        };
+        crate::identity::write_secret(path.as_ref(), &encode_cbor(&file)?)?;
+
+        tracing::info!(path = ?path.as_ref(), "exported backup");
+        Ok(())
+    }
+
+    /// Restore a backup written by [`Node::export_backup`] into the data
+    /// directory of `config`, which must be empty or not exist yet.
+    ///
+    /// The restored state is protected with the passphrase in `config`, not
+    /// the one used for the backup. Start the node afterwards with
+    /// [`Node::open`].
+    ///
+    /// Everything is written to a directory next to the data directory
+    /// first, and only moved into place once the import has succeeded, so a
+    /// failed import leaves nothing behind.
+    ///
+    /// The restored node has the same identity as the one that was backed
+    /// up, and carries on the same logs. Stop using the original node for
+    /// good before starting the restored one: if both author operations, they
+    /// fork their logs, and peers will stop accepting them from either.
+    pub async fn import_backup(
+        path: impl AsRef<Path>,
+        passphrase: &Passphrase,
+        config: &NodeConfig,
+    ) -> anyhow::Result<()> {
+        let dir = config
+            .data_dir()
+            .ok_or_else(|| anyhow!("a data directory is needed to import a backup"))?;
+        if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+            return Err(anyhow!("data directory is not empty: {}", dir.display()));
+        }
+
+        let file: BackupFile = decode_cbor(
+            std::fs::read(path.as_ref())
+                .context("read backup")?
+                .as_slice(),
+        )?;
+        if file.version != BACKUP_VERSION {
+            return Err(anyhow!("unsupported backup version {}", file.version));
+        }
+        let contents = file
+            .kdf
+            .derive(passphrase)?
+            .open(&file.contents)
+            .map_err(|_| anyhow!("wrong passphrase"))?;
+        let contents: BackupContents = decode_cbor(contents.as_slice())?;
+
+        let mut staging = dir.as_os_str().to_owned();
+        staging.push(".import");
+        let staging = PathBuf::from(staging);
+        if staging.exists() {
+            // Left over from an import that was interrupted
+            std::fs::remove_dir_all(&staging)?;
+        }
+        let staged = NodeConfig {
+            storage: StorageConfig::DataDir(staging.clone()),
+            ..config.clone()
+        };
+        if let Err(err) = restore_into(&staged, contents).await {
+            std::fs::remove_dir_all(&staging).ok();
+            return Err(err);
+        }
+
+        if dir.exists() {
+            std::fs::remove_dir(dir)?;
+        }
+        std::fs::rename(&staging, dir).context("move imported data into place")?;
+
+        tracing::info!(path = ?path.as_ref(), ?dir, "imported backup");
+        Ok(())
+    }
+}
+
+/// Write the contents of a backup into the (empty) data directory of
+/// `config`.
+async fn restore_into(config: &NodeConfig, contents: BackupContents) -> anyhow::Result<()> {
+    let dir = config.data_dir().expect("data directory is set");
+    let keyring = config.unlock()?.expect("data directory is set");
+    crate::identity::write_secret(
+        &dir.join(PRIVATE_KEY_FILE),
+        &keyring.data_key().seal(&contents.private_key),
+    )?;
+
+    let db = Database::open(dir.join(DATABASE_FILE), keyring.data_key().clone())
+        .context("open database")?;
+    for (namespace, key, value) in contents.entries {
+        db.put_entry(&namespace, &key, &value)?;
+    }
+
+    let mut op_store = OpStore::from(SqliteStore::new(db));
+    for (topic, header_bytes, body) in contents.operations {
+        let header: Header<Extensions> = decode_cbor(header_bytes.as_slice())?;
+        let body = body.map(|body| Body::new(&body));
+        op_store
+            .insert_operation(header.hash(), &header, body.as_ref(), &header_bytes, &topic)
+            .await?;
+    }
+    Ok(())
+}
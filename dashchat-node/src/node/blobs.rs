@@ -0,0 +1,426 @@
+use p2panda_core::Hash;
+use tokio::sync::Notify;
+
+use crate::{
+    AttachmentRef,
+    blobs::{self, BlobItem, BlobMessage, BlobProgress, MAX_BLOB_SIZE},
+    keyring::DataKey,
+    operation::encode_blob_message,
+};
+
+use super::*;
+
+impl Node {
+    /// Share a file of up to [`MAX_BLOB_SIZE`] bytes in a chat.
+    ///
+    /// The file is encrypted with a key of its own and kept by this node as
+    /// a blob. Only a reference to it is sent in the message; members fetch
+    /// the blob itself with [`Node::download_attachment`].
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn send_attachment(
+        &self,
+        chat_id: ChatId,
+        name: String,
+        mime_type: String,
+        data: &[u8],
+        caption: Option<String>,
+    ) -> anyhow::Result<ChatMessage> {
+        let attachment = self.store_blob(name, mime_type, data)?;
+        self.send_message(
+            chat_id,
+            ChatMessageContent::Attachment {
+                attachment,
+                caption,
+            },
+        )
+        .await
+    }
+
+    /// Encrypt a file and keep it as a blob, for members to fetch by the
+    /// returned reference.
+    pub(super) fn store_blob(
+        &self,
+        name: String,
+        mime_type: String,
+        data: &[u8],
+    ) -> anyhow::Result<AttachmentRef> {
+        if data.len() > MAX_BLOB_SIZE {
+            return Err(anyhow!(
+                "file is too large: {} bytes, at most {MAX_BLOB_SIZE} are allowed",
+                data.len()
+            ));
+        }
+
+        let key = DataKey::random();
+        let blob = blobs::encrypt(&key, data)?;
+        let hash = blob.manifest.hash()?;
+        self.blobs.put_blob(hash, blob)?;
+
+        Ok(AttachmentRef {
+            hash: *hash.as_bytes(),
+            key: key.to_bytes(),
+            name,
+            mime_type,
+            size: data.len() as u64,
+        })
+    }
+
+    /// Start fetching an attachment from the members of a chat who have it.
+    ///
+    /// Chunks are stored as they arrive, so calling this again after an
+    /// interrupted download only fetches what is still missing. Downloads
+    /// which were interrupted by a restart are resumed when the node starts.
+    /// Progress is reported through [`Node::subscribe_blob_progress`].
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn download_attachment(
+        &self,
+        chat_id: ChatId,
+        attachment: &AttachmentRef,
+    ) -> anyhow::Result<()> {
+        let hash = Hash::from_bytes(attachment.hash);
+        if let Some(progress) = self.blobs.progress(hash)? {
+            if progress.is_complete() {
+                self.blob_progress_tx.send(progress).ok();
+                return Ok(());
+            }
+        }
+        self.blobs.put_download(hash, chat_id)?;
+        self.spawn_download(chat_id, hash).await;
+        Ok(())
+    }
+
+    /// The contents of an attachment, if all of it has been downloaded.
+    pub async fn get_attachment(
+        &self,
+        attachment: &AttachmentRef,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(chunks) = self.blobs.read(Hash::from_bytes(attachment.hash))? else {
+            return Ok(None);
+        };
+        let data = blobs::decrypt(&DataKey::from_bytes(attachment.key), chunks)?;
+        if data.len() as u64 != attachment.size {
+            return Err(anyhow!(
+                "attachment is {} bytes instead of {}",
+                data.len(),
+                attachment.size
+            ));
+        }
+        Ok(Some(data))
+    }
+
+    /// Receive a [`BlobProgress`] whenever a download gets further.
+    pub fn subscribe_blob_progress(&self) -> broadcast::Receiver<BlobProgress> {
+        self.blob_progress_tx.subscribe()
+    }
+
+    /// Resume every download which hadn't completed when the node stopped.
+    pub(super) async fn resume_downloads(&self) -> anyhow::Result<()> {
+        for (hash, chat_id) in self.blobs.downloads()? {
+            tracing::debug!(hash = hash.short(), ?chat_id, "resuming download");
+            self.spawn_download(chat_id, hash).await;
+        }
+        Ok(())
+    }
+
+    /// Keep asking for whatever is missing of a blob until we have all of it,
+    /// or nobody has answered for a while.
+    async fn spawn_download(&self, chat_id: ChatId, hash: Hash) {
+        let notify = Arc::new(Notify::new());
+        {
+            let mut downloads = self.downloads.write().await;
+            if downloads.contains_key(&hash) {
+                return;
+            }
+            downloads.insert(hash, notify.clone());
+        }
+
+        let node = self.clone();
+        tokio::spawn(
+            async move {
+                let mut idle_rounds = 0;
+                while idle_rounds < BLOB_IDLE_ROUNDS {
+                    let want = match node.next_want(hash) {
+                        Ok(Some(want)) => want,
+                        Ok(None) => {
+                            tracing::debug!(hash = hash.short(), "download complete");
+                            node.blobs
+                                .remove_download(hash)
+                                .ok_or_warn("failed to remove finished download");
+                            break;
+                        }
+                        Err(err) => {
+                            tracing::error!(?err, "download error");
+                            break;
+                        }
+                    };
+                    node.send_blob_message(chat_id, &want)
+                        .await
+                        .ok_or_warn("failed to request blob");
+
+                    // Wait until everything asked for has arrived, or give up
+                    // on this round and ask again.
+                    let deadline = tokio::time::Instant::now() + BLOB_REQUEST_TIMEOUT;
+                    idle_rounds += 1;
+                    while !node.has_wanted(&want).unwrap_or(false) {
+                        match tokio::time::timeout_at(deadline, notify.notified()).await {
+                            Ok(()) => idle_rounds = 0,
+                            Err(_) => break,
+                        }
+                    }
+                    if node.has_wanted(&want).unwrap_or(false) {
+                        idle_rounds = 0;
+                    }
+                }
+                if idle_rounds >= BLOB_IDLE_ROUNDS {
+                    tracing::warn!(hash = hash.short(), "nobody has the blob, pausing download");
+                }
+                node.downloads.write().await.remove(&hash);
+            }
+            .instrument(tracing::info_span!("download", hash = hash.short())),
+        );
+    }
+
+    /// What to ask for next to complete a blob, if anything.
+    fn next_want(&self, hash: Hash) -> anyhow::Result<Option<BlobMessage>> {
+        let Some(manifest) = self.blobs.manifest(hash)? else {
+            return Ok(Some(BlobMessage::Want {
+                hash,
+                manifest: true,
+                pages: vec![],
+                chunks: vec![],
+            }));
+        };
+        // Chunks can only be checked once we have the pages listing them
+        let pages = self.blobs.missing_pages(hash, &manifest)?;
+        if !pages.is_empty() {
+            return Ok(Some(BlobMessage::Want {
+                hash,
+                manifest: false,
+                pages: pages.into_iter().take(BLOB_REQUEST_CHUNKS).collect(),
+                chunks: vec![],
+            }));
+        }
+        let missing = self.blobs.missing(hash, &manifest)?;
+        Ok((!missing.is_empty()).then(|| BlobMessage::Want {
+            hash,
+            manifest: false,
+            pages: vec![],
+            chunks: missing.into_iter().take(BLOB_REQUEST_CHUNKS).collect(),
+        }))
+    }
+
+    fn has_wanted(&self, want: &BlobMessage) -> anyhow::Result<bool> {
+        let BlobMessage::Want {
+            hash,
+            manifest,
+            pages,
+            chunks,
+        } = want
+        else {
+            return Ok(true);
+        };
+        if *manifest {
+            return Ok(self.blobs.manifest(*hash)?.is_some());
+        }
+        for index in pages {
+            if self.blobs.page(*hash, *index)?.is_none() {
+                return Ok(false);
+            }
+        }
+        for index in chunks {
+            if !self.blobs.has_chunk(*hash, *index)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn send_blob_message(
+        &self,
+        chat_id: ChatId,
+        message: &BlobMessage,
+    ) -> anyhow::Result<()> {
+        let sender = self
+            .chats
+            .read()
+            .await
+            .get(&chat_id)
+            .map(|chat| chat.sender.clone())
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        sender
+            .send(ToNetwork::Message {
+                bytes: encode_blob_message(message)?,
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub(super) async fn process_blob_message(
+        &self,
+        topic: Topic,
+        message: BlobMessage,
+    ) -> anyhow::Result<()> {
+        let Topic::Chat(chat_id) = topic else {
+            return Err(anyhow!("blob message outside of a chat: {topic:?}"));
+        };
+        // Somebody else answered, so we needn't
+        if let Some((hash, item)) = message.answers() {
+            if let Some(pending) = self.blob_answers.write().await.get_mut(&hash) {
+                pending.remove(&item);
+            }
+        }
+
+        match message {
+            BlobMessage::Want {
+                hash,
+                manifest: want_manifest,
+                pages,
+                chunks,
+            } => {
+                if self.blobs.manifest(hash)?.is_none() {
+                    return Ok(());
+                }
+                let mut items = vec![];
+                if want_manifest {
+                    items.push(BlobItem::Manifest);
+                }
+                for index in pages.into_iter().take(BLOB_REQUEST_CHUNKS) {
+                    if self.blobs.page(hash, index)?.is_some() {
+                        items.push(BlobItem::Page(index));
+                    }
+                }
+                for index in chunks.into_iter().take(BLOB_REQUEST_CHUNKS) {
+                    if self.blobs.has_chunk(hash, index)? {
+                        items.push(BlobItem::Chunk(index));
+                    }
+                }
+                if items.is_empty() {
+                    return Ok(());
+                }
+                let scheduled = {
+                    let mut answers = self.blob_answers.write().await;
+                    let scheduled = answers.contains_key(&hash);
+                    answers.entry(hash).or_default().extend(items);
+                    scheduled
+                };
+                if !scheduled {
+                    self.spawn_answer(chat_id, hash);
+                }
+            }
+            BlobMessage::Manifest { hash, manifest } => {
+                let Some(notify) = self.downloads.read().await.get(&hash).cloned() else {
+                    return Ok(());
+                };
+                if self.blobs.manifest(hash)?.is_some() {
+                    return Ok(());
+                }
+                if manifest.hash()? != hash {
+                    return Err(anyhow!("manifest does not match blob {}", hash.short()));
+                }
+                if !manifest.is_valid() {
+                    return Err(anyhow!("blob {} has an invalid manifest", hash.short()));
+                }
+                self.blobs.put_manifest(hash, &manifest)?;
+                self.notify_progress(hash)?;
+                notify.notify_one();
+            }
+            BlobMessage::Page { hash, index, page } => {
+                let Some(notify) = self.downloads.read().await.get(&hash).cloned() else {
+                    return Ok(());
+                };
+                let Some(manifest) = self.blobs.manifest(hash)? else {
+                    return Ok(());
+                };
+                if manifest.pages.get(index as usize) != Some(&page.hash()?)
+                    || page.chunks.len() != manifest.page_len(index)
+                {
+                    return Err(anyhow!("page {index} does not match blob {}", hash.short()));
+                }
+                if self.blobs.page(hash, index)?.is_some() {
+                    return Ok(());
+                }
+                self.blobs.put_page(hash, index, &page)?;
+                notify.notify_one();
+            }
+            BlobMessage::Chunk { hash, index, bytes } => {
+                let Some(notify) = self.downloads.read().await.get(&hash).cloned() else {
+                    return Ok(());
+                };
+                let Some(expected) = self.blobs.chunk_hash(hash, index)? else {
+                    return Ok(());
+                };
+                if expected != Hash::new(&bytes) {
+                    return Err(anyhow!(
+                        "chunk {index} does not match blob {}",
+                        hash.short()
+                    ));
+                }
+                if self.blobs.has_chunk(hash, index)? {
+                    return Ok(());
+                }
+                self.blobs.put_chunk(hash, index, bytes)?;
+                self.notify_progress(hash)?;
+                notify.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    /// Send whatever is still to be sent of a blob after a random delay,
+    /// unless another holder sends it first.
+    fn spawn_answer(&self, chat_id: ChatId, hash: Hash) {
+        let node = self.clone();
+        tokio::spawn(
+            async move {
+                tokio::time::sleep(rand::random_range(Duration::ZERO..BLOB_ANSWER_DELAY)).await;
+                let items = node
+                    .blob_answers
+                    .write()
+                    .await
+                    .remove(&hash)
+                    .unwrap_or_default();
+                for item in items {
+                    node.send_blob_item(chat_id, hash, item)
+                        .await
+                        .ok_or_warn("failed to answer blob request");
+                }
+            }
+            .instrument(tracing::info_span!("answer", hash = hash.short())),
+        );
+    }
+
+    async fn send_blob_item(
+        &self,
+        chat_id: ChatId,
+        hash: Hash,
+        item: BlobItem,
+    ) -> anyhow::Result<()> {
+        let message = match item {
+            BlobItem::Manifest => self
+                .blobs
+                .manifest(hash)?
+                .map(|manifest| BlobMessage::Manifest { hash, manifest }),
+            BlobItem::Page(index) => {
+                self.blobs
+                    .page(hash, index)?
+                    .map(|page| BlobMessage::Page { hash, index, page })
+            }
+            BlobItem::Chunk(index) => self
+                .blobs
+                .chunk(hash, index)?
+                .map(|bytes| BlobMessage::Chunk { hash, index, bytes }),
+        };
+        if let Some(message) = message {
+            self.send_blob_message(chat_id, &message).await?;
+        }
+        Ok(())
+    }
+
+    fn notify_progress(&self, hash: Hash) -> anyhow::Result<()> {
+        if let Some(progress) = self.blobs.progress(hash)? {
+            // Nobody listening is fine.
+            self.blob_progress_tx.send(progress).ok();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use crate::chat::{GroupInfo, GroupInfoUpdate};
+
+use super::*;
+
+impl Node {
+    /// A chat's name, description and avatar. Chats whose info has never
+    /// been set have the default, empty info.
+    pub async fn group_info(&self, chat_id: ChatId) -> anyhow::Result<GroupInfo> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        Ok(chat
+            .group_info()
+            .map(|(_, info)| info.clone())
+            .unwrap_or_default())
+    }
+
+    /// Replace a chat's info.
+    ///
+    /// The info is published to the chat as a new version of it, so that it
+    /// is encrypted like any other message. Only admins can change it.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn set_group_info(&self, chat_id: ChatId, info: GroupInfo) -> anyhow::Result<()> {
+        let version = {
+            let chats = self.chats.read().await;
+            let chat = chats
+                .get(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            chat.group_info().map_or(0, |(version, _)| version) + 1
+        };
+        self.send_message(chat_id, ChatMessageContent::GroupInfo { info, version })
+            .await?;
+        Ok(())
+    }
+
+    /// Replace a chat's avatar with an image, kept as a blob like an
+    /// attachment. Set the info without an avatar to remove it.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn set_group_avatar(
+        &self,
+        chat_id: ChatId,
+        name: String,
+        mime_type: String,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let info = GroupInfo {
+            avatar: Some(self.store_blob(name, mime_type, data)?),
+            ..self.group_info(chat_id).await?
+        };
+        self.set_group_info(chat_id, info).await
+    }
+
+    /// Receive a [`GroupInfoUpdate`] whenever the info of a chat changes.
+    pub fn subscribe_group_info(&self) -> broadcast::Receiver<GroupInfoUpdate> {
+        self.group_info_tx.subscribe()
+    }
+
+    pub(super) fn emit_group_info(&self, chat: &Chat) {
+        if let Some((_, info)) = chat.group_info() {
+            // Nobody listening is fine.
+            self.group_info_tx
+                .send(GroupInfoUpdate {
+                    chat_id: chat.id,
+                    info: info.clone(),
+                })
+                .ok();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::invite::InviteToken;
+use crate::spaces::MemberCode;
+
+use super::*;
+
+impl Node {
+    /// Make an invite which lets whoever holds it join a chat with `role`,
+    /// until `lifetime` has passed. A single-use invite only lets the first
+    /// holder in.
+    ///
+    /// Holders join through us: our node has to be online to add them.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn create_invite(
+        &self,
+        chat_id: ChatId,
+        role: Role,
+        lifetime: Duration,
+        single_use: bool,
+    ) -> anyhow::Result<InviteToken> {
+        if !self.chats.read().await.contains_key(&chat_id) {
+            return Err(anyhow!("Chat not found: {chat_id}"));
+        }
+        if !self.own_role(chat_id).await?.may_add(role) {
+            return Err(anyhow!("Not allowed to invite members as {role:?}"));
+        }
+        InviteToken::new(
+            &self.private_key,
+            self.me().await?.into(),
+            chat_id,
+            role,
+            timestamp_now().saturating_add(lifetime.as_secs()),
+            single_use,
+        )
+    }
+
+    /// Ask the member who made an invite to add us to its chat. We join the
+    /// chat once they have.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn redeem_invite(&self, invite: InviteToken) -> anyhow::Result<ChatId> {
+        invite.verify(timestamp_now())?;
+        let inviter = invite.inviter();
+        if inviter == self.public_key() {
+            return Err(anyhow!("Can't redeem your own invite"));
+        }
+        if !self.friends.read().await.contains_key(&inviter) {
+            self.add_friend(invite.inviter.clone().into()).await?;
+        }
+
+        let chat_id = invite.chat_id;
+        self.author_operation(
+            inviter.into(),
+            Payload::Invitation(InvitationMessage::JoinRequest {
+                invite,
+                member: self.me().await?.into(),
+            }),
+        )
+        .await?;
+
+        Ok(chat_id)
+    }
+
+    /// Add the sender of a join request to the chat of one of our invites,
+    /// if the invite is still valid.
+    pub(super) async fn process_join_request(
+        &self,
+        sender: PK,
+        invite: &InviteToken,
+        member: &MemberCode,
+    ) -> anyhow::Result<()> {
+        let chat_id = invite.chat_id;
+        if member.public_key() != sender {
+            return Err(anyhow!("Join request for someone else: {sender}"));
+        }
+        if invite.inviter() != self.public_key() {
+            return Err(anyhow!("Join request with someone else's invite"));
+        }
+        let key = (invite.nonce, sender);
+        if self.redeemed_invites.read().await.contains(&key) {
+            tracing::debug!(?chat_id, ?sender, "join request already handled");
+            return Ok(());
+        }
+        invite.verify(timestamp_now())?;
+        if invite.single_use
+            && self
+                .redeemed_invites
+                .read()
+                .await
+                .iter()
+                .any(|(nonce, _)| *nonce == invite.nonce)
+        {
+            return Err(anyhow!("Invite has already been used"));
+        }
+
+        if !self.friends.read().await.contains_key(&sender) {
+            self.add_friend(member.clone().into()).await?;
+        }
+        self.add_member(chat_id, sender, invite.role).await?;
+
+        if let Some(db) = &self.db {
+            db.put(KV_REDEEMED_INVITES, &key, &())?;
+        }
+        self.redeemed_invites.write().await.insert(key);
+        tracing::info!(?chat_id, ?sender, "added member with invite");
+        Ok(())
+    }
+}
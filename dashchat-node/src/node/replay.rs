@@ -0,0 +1,96 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use p2panda_core::{Hash, Operation};
+
+use crate::ShortId;
+
+use super::*;
+
+impl Node {
+    /// Rebuild in-memory state for a topic from the operations already in
+    /// the op store, without waiting for the network or sending
+    /// notifications.
+    ///
+    /// Space control messages are already reflected in the persisted spaces
+    /// state, so, just as when authoring, only Application messages are
+    /// processed again.
+    pub(super) async fn replay_topic(&self, topic: Topic) -> anyhow::Result<()> {
+        let mut operations = vec![];
+        for (public_key, _) in self.op_store.get_log_heights(&topic).await? {
+            let log = self
+                .op_store
+                .get_log(&public_key, &topic, None)
+                .await?
+                .unwrap_or_default();
+            operations.extend(log.into_iter().map(|(header, body)| Operation {
+                hash: header.hash(),
+                header,
+                body,
+            }));
+        }
+
+        let operations = causal_order(operations);
+        tracing::debug!(?topic, num = operations.len(), "replaying operations");
+
+        for operation in operations {
+            let hash = operation.hash;
+            if let Err(err) = self
+                .apply_operation(topic, operation, self.author_store.clone(), true)
+                .await
+            {
+                tracing::error!(?topic, hash = hash.short(), ?err, "replay operation error");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sort operations so that each one comes after its backlink and any
+/// `previous` dependencies within the same set. Independent operations are
+/// ordered by timestamp.
+fn causal_order(mut operations: Vec<Operation<Extensions>>) -> Vec<Operation<Extensions>> {
+    operations.sort_by_key(|op| (op.header.timestamp, op.header.seq_num));
+
+    let index: HashMap<Hash, usize> = operations
+        .iter()
+        .enumerate()
+        .map(|(i, op)| (op.hash, i))
+        .collect();
+
+    let mut blocking = vec![0; operations.len()];
+    let mut dependents = vec![vec![]; operations.len()];
+    for (i, op) in operations.iter().enumerate() {
+        let deps = op
+            .header
+            .backlink
+            .iter()
+            .chain(op.header.previous.iter())
+            .filter_map(|hash| index.get(hash).copied())
+            .collect::<HashSet<_>>();
+        blocking[i] = deps.len();
+        for dep in deps {
+            dependents[dep].push(i);
+        }
+    }
+
+    let mut ready = (0..operations.len())
+        .filter(|i| blocking[*i] == 0)
+        .collect::<BTreeSet<_>>();
+    let mut order = Vec::with_capacity(operations.len());
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for &j in &dependents[i] {
+            blocking[j] -= 1;
+            if blocking[j] == 0 {
+                ready.insert(j);
+            }
+        }
+    }
+
+    let mut operations = operations.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .filter_map(|i| operations[i].take())
+        .collect()
+}
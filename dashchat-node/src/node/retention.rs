@@ -0,0 +1,185 @@
+use crate::spaces::{ArgType, SpaceControlMessage};
+
+use super::*;
+
+impl Node {
+    /// Keep messages in a chat for `lifetime` from when they were sent, or
+    /// forever with `None`.
+    ///
+    /// The setting is published to the chat, so that every member applies
+    /// it: expired messages are forgotten, their payloads purged, and each
+    /// member prunes the operations behind them from their own log.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn set_retention(
+        &self,
+        chat_id: ChatId,
+        lifetime: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        let lifetime = lifetime.map(|lifetime| lifetime.as_secs());
+        if lifetime == Some(0) {
+            return Err(anyhow!("messages have to be kept for at least a second"));
+        }
+        self.send_message(chat_id, ChatMessageContent::Retention { lifetime })
+            .await?;
+        Ok(())
+    }
+
+    /// How long messages in a chat are kept, if not forever.
+    pub async fn get_retention(&self, chat_id: ChatId) -> anyhow::Result<Option<Duration>> {
+        let chats = self.chats.read().await;
+        let chat = chats
+            .get(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        Ok(chat.lifetime().map(Duration::from_secs))
+    }
+
+    /// Expire messages every [`RETENTION_INTERVAL`] for as long as the node
+    /// runs.
+    pub(super) fn spawn_retention_loop(&self) {
+        let node = self.clone();
+        tokio::spawn(
+            async move {
+                let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    node.expire_messages()
+                        .await
+                        .ok_or_warn("failed to expire messages");
+                }
+            }
+            .instrument(tracing::info_span!("retention_loop")),
+        );
+    }
+
+    async fn expire_messages(&self) -> anyhow::Result<()> {
+        let now = timestamp_now();
+        let (expired, due) = {
+            let mut chats = self.chats.write().await;
+            let expired = chats
+                .values_mut()
+                .map(|chat| (chat.id, chat.expire(now)))
+                .filter(|(_, expired)| !expired.is_empty())
+                .collect::<Vec<_>>();
+            let due = chats
+                .values()
+                .filter(|chat| {
+                    chat.lifetime()
+                        .is_some_and(|lifetime| chat.prune_due(now, prune_interval(lifetime)))
+                })
+                .map(|chat| chat.id)
+                .collect::<Vec<_>>();
+            (expired, due)
+        };
+
+        for (chat_id, message_ids) in expired {
+            tracing::debug!(?chat_id, num = message_ids.len(), "messages expired");
+            for message_id in message_ids {
+                self.purge_message(message_id)
+                    .await
+                    .ok_or_warn("failed to purge expired message");
+            }
+        }
+        for chat_id in due {
+            self.prune_log(chat_id, now).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace our own log in a chat with pruning operations, if any of it
+    /// has expired or been deleted.
+    ///
+    /// Space control messages are needed by anyone who joins later, and the
+    /// retention setting and group info in force have to outlive the messages
+    /// around them, as do the moderation settings which deletions name. Those
+    /// are carried over along with the messages which haven't expired yet,
+    /// but not those which have been deleted since they were published.
+    ///
+    /// The first operation prunes the log and the rest are appended to it,
+    /// each small enough for a gossip message.
+    async fn prune_log(&self, chat_id: ChatId, now: u64) -> anyhow::Result<()> {
+        let (lifetime, settings, purged) = {
+            let mut chats = self.chats.write().await;
+            let chat = chats
+                .get_mut(&chat_id)
+                .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+            chat.set_pruned(now);
+            let settings: Vec<MessageId> = chat
+                .retention_setting()
+                .into_iter()
+                .chain(chat.group_info_setting())
+                .chain(chat.moderation_settings())
+                .collect();
+            (chat.lifetime(), settings, chat.purged())
+        };
+        let Some(lifetime) = lifetime else {
+            return Ok(());
+        };
+
+        let topic = Topic::Chat(chat_id);
+        let log = self
+            .op_store
+            .get_log(&self.private_key.public_key(), &topic, None)
+            .await?
+            .unwrap_or_default();
+
+        let mut batches: Vec<Vec<SpaceControlMessage>> = vec![];
+        let (mut size, mut kept, mut dropped) = (0, 0, 0);
+        for (_, body) in log {
+            // Already purged
+            let Some(body) = body else {
+                dropped += 1;
+                continue;
+            };
+            let Payload::SpaceControl(msgs) = Payload::try_from_body(body)? else {
+                continue;
+            };
+            for msg in msgs {
+                let id = msg.hash.into();
+                let expired =
+                    msg.timestamp.saturating_add(lifetime) <= now && !settings.contains(&id);
+                if msg.arg_type() == ArgType::Application && (expired || purged.contains(&id)) {
+                    dropped += 1;
+                    continue;
+                }
+                let len = encode_cbor(&msg)?.len();
+                match batches.last_mut() {
+                    Some(batch) if size + len <= MAX_PRUNE_PAYLOAD => {
+                        batch.push(msg);
+                        size += len;
+                    }
+                    _ => {
+                        batches.push(vec![msg]);
+                        size = len;
+                    }
+                }
+                kept += 1;
+            }
+        }
+        if dropped == 0 {
+            return Ok(());
+        }
+
+        tracing::debug!(
+            ?chat_id,
+            dropped,
+            kept,
+            ops = batches.len().max(1),
+            "pruning own log"
+        );
+        let mut batches = batches.into_iter();
+        let first = batches.next().unwrap_or_default();
+        self.author_pruning_operation(topic.clone(), Payload::SpaceControl(first))
+            .await?;
+        for batch in batches {
+            self.author_operation(topic.clone(), Payload::SpaceControl(batch))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait between prunings of our log in a chat whose messages
+/// are kept for `lifetime` seconds.
+fn prune_interval(lifetime: u64) -> u64 {
+    (lifetime / 2).clamp(1, PRUNE_INTERVAL.as_secs())
+}
@@ -0,0 +1,106 @@
+use crate::{
+    chat::{Signal, SignalEvent, SignalPayload},
+    operation::encode_ephemeral_message,
+    spaces::{ArgType, SpaceControlMessage},
+};
+
+use super::*;
+
+impl Node {
+    /// Tell whoever in a chat is online right now about something short-lived,
+    /// such as that we are typing.
+    ///
+    /// The signal is encrypted for the group and gossiped, but never appended
+    /// to a log: members who are offline never see it.
+    #[tracing::instrument(skip_all, fields(me = ?self.public_key()))]
+    pub async fn send_signal(&self, chat_id: ChatId, signal: Signal) -> anyhow::Result<()> {
+        let space = self
+            .manager
+            .space(chat_id)
+            .await?
+            .ok_or_else(|| anyhow!("Chat has no Space: {chat_id}"))?;
+        let sender = self
+            .chats
+            .read()
+            .await
+            .get(&chat_id)
+            .map(|chat| chat.sender.clone())
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+
+        let payload = SignalPayload {
+            signal,
+            author: self.public_key(),
+            timestamp: timestamp_now(),
+        };
+        let encrypted = space.publish(&payload.as_bytes()?).await?;
+        sender
+            .send(ToNetwork::Message {
+                bytes: encode_ephemeral_message(&encrypted)?,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Receive every signal from other members as it arrives.
+    pub fn subscribe_signals(&self) -> broadcast::Receiver<SignalEvent> {
+        self.signal_tx.subscribe()
+    }
+
+    /// The signals in a chat which haven't expired yet.
+    pub async fn get_signals(&self, chat_id: ChatId) -> anyhow::Result<Vec<SignalEvent>> {
+        let mut chats = self.chats.write().await;
+        let chat = chats
+            .get_mut(&chat_id)
+            .ok_or_else(|| anyhow!("Chat not found: {chat_id}"))?;
+        Ok(chat.signals(timestamp_now()))
+    }
+
+    pub(super) async fn process_ephemeral(
+        &self,
+        topic: Topic,
+        message: SpaceControlMessage,
+    ) -> anyhow::Result<()> {
+        let Topic::Chat(chat_id) = topic else {
+            return Err(anyhow!("ephemeral message outside of a chat: {topic:?}"));
+        };
+        // Anything else would change the space without being stored
+        if message.arg_type() != ArgType::Application {
+            return Err(anyhow!(
+                "ephemeral space message of type {:?}",
+                message.arg_type()
+            ));
+        }
+
+        for event in self.manager.process(&message).await? {
+            let Event::Application { data, .. } = event else {
+                continue;
+            };
+            let payload = SignalPayload::from_bytes(&data)?;
+            if payload.author != PK::from(message.author()) {
+                return Err(anyhow!(
+                    "signal author {:?} does not match space message author",
+                    payload.author
+                ));
+            }
+            // A timestamp in the future can't make a signal last any longer
+            let now = timestamp_now();
+            let expires_at = payload.timestamp.min(now) + SIGNAL_TTL.as_secs();
+            if payload.author == self.public_key() || expires_at <= now {
+                continue;
+            }
+
+            let event = SignalEvent {
+                chat_id,
+                author: payload.author,
+                signal: payload.signal,
+                expires_at,
+            };
+            if let Some(chat) = self.chats.write().await.get_mut(&chat_id) {
+                chat.set_signal(event.author, event.signal, event.expires_at);
+            }
+            // Nobody listening is fine.
+            self.signal_tx.send(event).ok();
+        }
+        Ok(())
+    }
+}
@@ -8,7 +8,13 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
 use tokio_stream::Stream;
 
-use crate::{ShortId, operation::InvitationMessage, spaces::ArgType};
+use crate::{
+    ShortId,
+    chat::{ChatMessagePayload, MarkerKind},
+    operation::{GossipMessage, InvitationMessage},
+    spaces::{ArgType, SpaceControlMessage},
+    util::ResultExt,
+};
 
 use super::*;
 
@@ -43,12 +49,52 @@ impl Node {
 
         let (network_tx, _gossip_ready) = self.initialize_topic(chat_id.into()).await?;
 
-        let chat = Chat::new(chat_id, network_tx);
+        let chat = Chat::new(chat_id, self.public_key(), network_tx);
+        self.timelines
+            .write()
+            .await
+            .insert(chat_id, chat.timeline());
         self.chats.write().await.insert(chat_id, chat.clone());
 
+        if let Some(db) = &self.db {
+            db.put(KV_CHATS, &chat_id, &())?;
+        }
+
         Ok(chat)
     }
 
+    /// Stop following a chat we are no longer a member of: unsubscribe from
+    /// its topic, stop processing its operations and forget it.
+    ///
+    /// The operations and Space state we already have are kept.
+    pub(super) async fn close_group(&self, chat_id: ChatId) -> anyhow::Result<()> {
+        let topic = Topic::Chat(chat_id);
+        let left_at = timestamp_now();
+        tracing::info!(?chat_id, "closing group");
+
+        // Dropping the chat drops our sender to the gossip overlay
+        self.chats.write().await.remove(&chat_id);
+        self.timelines.write().await.remove(&chat_id);
+        self.pending_markers
+            .write()
+            .await
+            .retain(|(id, _), _| *id != chat_id);
+        self.author_store.remove_topic(&topic).await;
+
+        self.left_chats.write().await.insert(chat_id, left_at);
+        if let Some(db) = &self.db {
+            db.remove(KV_CHATS, &chat_id)?;
+            db.put(KV_LEFT_CHATS, &chat_id, &left_at)?;
+        }
+
+        // Last, since this may be the task we are running in. Dropping the
+        // stream drops our receiver from the gossip overlay.
+        if let Some(task) = self.stream_tasks.write().await.remove(&topic) {
+            task.abort();
+        }
+        Ok(())
+    }
+
     /// Internal function to start the necessary tasks for processing group chat
     /// network activity.
     ///
@@ -63,18 +109,36 @@ impl Node {
         tracing::debug!(?topic, "subscribed to topic");
 
         let stream = ReceiverStream::new(network_rx);
-        let stream = stream.filter_map(|event| async {
-            match event {
-                FromNetwork::GossipMessage { bytes, .. } => match decode_gossip_message(&bytes) {
-                    Ok(result) => Some(result),
-                    Err(err) => {
-                        tracing::warn!(?err, "decode gossip message error");
-                        None
-                    }
-                },
-                FromNetwork::SyncMessage {
-                    header, payload, ..
-                } => Some((header, payload)),
+        let node = self.clone();
+        let stream = stream.filter_map(move |event| {
+            let node = node.clone();
+            async move {
+                match event {
+                    FromNetwork::GossipMessage { bytes, .. } => match decode_gossip_message(&bytes)
+                    {
+                        Ok(GossipMessage::Operation(header, payload)) => Some((header, payload)),
+                        // Ephemeral messages bypass the op store entirely
+                        Ok(GossipMessage::Ephemeral(message)) => {
+                            node.process_ephemeral(topic, message)
+                                .await
+                                .ok_or_warn("process ephemeral message error");
+                            None
+                        }
+                        Ok(GossipMessage::Blob(message)) => {
+                            node.process_blob_message(topic, message)
+                                .await
+                                .ok_or_warn("process blob message error");
+                            None
+                        }
+                        Err(err) => {
+                            tracing::warn!(?err, "decode gossip message error");
+                            None
+                        }
+                    },
+                    FromNetwork::SyncMessage {
+                        header, payload, ..
+                    } => Some((header, payload)),
+                }
             }
         });
 
@@ -108,7 +172,10 @@ impl Node {
             });
 
         let author_store = self.author_store.clone();
-        self.spawn_stream_process_loop(stream, author_store, topic.clone());
+        let task = self.spawn_stream_process_loop(stream, author_store, topic.clone());
+        if let Some(previous) = self.stream_tasks.write().await.insert(topic, task) {
+            previous.abort();
+        }
 
         Ok((network_tx, gossip_ready))
     }
@@ -118,7 +185,7 @@ impl Node {
         stream: impl Stream<Item = Operation<Extensions>> + Send + 'static,
         author_store: AuthorStore<Topic>,
         topic: Topic,
-    ) {
+    ) -> task::AbortHandle {
         let node = self.clone();
         let mut stream = Box::pin(stream);
         task::spawn(
@@ -148,7 +215,8 @@ impl Node {
                 "stream_process_loop",
                 topic = format!("{:?}", topic)
             )),
-        );
+        )
+        .abort_handle()
     }
 
     // async fn enforce_ordering(
@@ -175,6 +243,25 @@ impl Node {
         author_store: AuthorStore<Topic>,
         is_author: bool,
     ) -> anyhow::Result<()> {
+        let (header, payload) = self
+            .apply_operation(topic, operation, author_store, is_author)
+            .await?;
+
+        if let Some(payload) = payload.as_ref() {
+            self.notify_payload(&header, payload).await?;
+        }
+
+        anyhow::Ok(())
+    }
+
+    /// Update local state from an operation without notifying anyone.
+    pub(super) async fn apply_operation(
+        &self,
+        topic: Topic,
+        operation: Operation<Extensions>,
+        author_store: AuthorStore<Topic>,
+        is_author: bool,
+    ) -> anyhow::Result<(Header<Extensions>, Option<Payload>)> {
         let Operation { header, body, hash } = operation;
 
         // NOTE: this is very much needed!!
@@ -184,12 +271,16 @@ impl Node {
 
         let payload = body.map(|body| Payload::try_from_body(body)).transpose()?;
 
-        match payload.as_ref() {
+        // Space messages carried again by an operation which pruned its log
+        // have already been processed
+        let mut fresh = payload.clone();
+        match fresh.as_mut() {
             Some(Payload::SpaceControl(msgs)) => {
                 let mut sd = self.space_dependencies.write().await;
-                for msg in msgs {
-                    sd.insert(msg.id(), hash.clone());
-                }
+                msgs.retain(|msg| {
+                    sd.insert(msg.id(), hash.clone())
+                        .is_none_or(|previous| previous == hash)
+                });
             }
             _ => {}
         }
@@ -197,7 +288,7 @@ impl Node {
         tracing::trace!(?payload, "RECEIVED OPERATION");
 
         if let Err(err) = self
-            .process_payload(topic, &header, payload.as_ref(), is_author)
+            .process_payload(topic, &header, fresh.as_ref(), is_author)
             .await
         {
             tracing::error!(?payload, ?err, "process operation error");
@@ -205,11 +296,7 @@ impl Node {
 
         tracing::debug!(hash = hash.short(), "processed operation");
 
-        if let Some(payload) = payload.as_ref() {
-            self.notify_payload(&header, payload).await?;
-        }
-
-        anyhow::Ok(())
+        Ok((header, payload))
     }
 
     pub async fn notify_payload(
@@ -241,10 +328,22 @@ impl Node {
         match (topic, &payload) {
             (Topic::Chat(chat_id), Some(Payload::SpaceControl(msgs))) => {
                 let mut chats = self.chats.write().await;
-                let chat = chats.get_mut(&chat_id).unwrap();
+                let Some(chat) = chats.get_mut(&chat_id) else {
+                    tracing::debug!(?chat_id, "operation for a chat we have left");
+                    return Ok(());
+                };
                 let types: Vec<_> = msgs.iter().map(|m| m.arg_type()).collect();
                 tracing::debug!(?types, "processing space msgs");
                 for msg in msgs {
+                    if !chat.roles.record(msg) {
+                        tracing::warn!(
+                            ?chat_id,
+                            author = ?PK::from(msg.author()),
+                            opid = msg.id().short(),
+                            "rejecting space msg not allowed by the author's role"
+                        );
+                        continue;
+                    }
                     // While authoring, all message types other than Application
                     // are already processed
                     if is_author && msg.arg_type() != ArgType::Application {
@@ -261,7 +360,7 @@ impl Node {
                             for (i, event) in events.into_iter().enumerate() {
                                 // TODO: need to get this from the space message, not the header!
                                 // because the welcome message could be passed from a differetn author
-                                self.process_chat_event(chat, event)
+                                self.process_chat_event(chat, msg, event)
                                     .instrument(tracing::info_span!("chat event loop", ?i))
                                     .await?;
                             }
@@ -289,6 +388,10 @@ impl Node {
                         }
                     }
                 }
+                if chat.removed {
+                    drop(chats);
+                    self.close_group(chat_id).await?;
+                }
             }
             (Topic::Inbox(public_key), Some(Payload::Invitation(invitation))) => {
                 if public_key != self.public_key() {
@@ -298,12 +401,29 @@ impl Node {
                 tracing::debug!(?invitation, "received invitation message");
                 match invitation {
                     InvitationMessage::JoinGroup(chat_id) => {
-                        self.join_group(*chat_id).await?;
-                        // TODO: maybe close down the chat tasks if we are kicked out?
+                        let left_at = self.left_chats.read().await.get(chat_id).copied();
+                        if left_at.is_some_and(|left_at| header.timestamp <= left_at) {
+                            tracing::debug!(?chat_id, "ignoring invitation from before we left");
+                        } else {
+                            self.join_group(*chat_id).await?;
+                        }
                     }
                     InvitationMessage::Friend => {
                         tracing::debug!("received friend invitation from: {:?}", header.public_key);
                     }
+                    InvitationMessage::JoinRequest { invite, member } => {
+                        self.process_join_request(header.public_key.into(), invite, member)
+                            .await
+                            .ok_or_warn("rejected join request");
+                    }
+                    InvitationMessage::RemovedFromGroup(chat_id) => {
+                        // Whether we really are removed is up to the space
+                        tracing::info!(
+                            ?chat_id,
+                            from = ?PK::from(header.public_key),
+                            "told we were removed from group"
+                        );
+                    }
                 }
             }
             (topic, payload) => {
@@ -316,13 +436,94 @@ impl Node {
     async fn process_chat_event(
         &self,
         chat: &mut Chat,
+        msg: &SpaceControlMessage,
         event: Event<ChatId>,
     ) -> anyhow::Result<()> {
         match event {
-            Event::Application { data, .. } => {
-                let message = ChatMessage::from_bytes(&data)?;
-                chat.messages.insert(message);
-            }
+            Event::Application { data, .. } => match ChatMessagePayload::from_bytes(&data) {
+                Ok(payload) if payload.author != PK::from(msg.author()) => {
+                    tracing::warn!(
+                        ?chat.id,
+                        claimed = ?payload.author,
+                        "chat message author does not match space message author"
+                    );
+                }
+                Ok(payload) => {
+                    // Judged by the author's role where they sent it, and
+                    // rejected if they weren't a member there at all
+                    let dependencies = msg.dependencies();
+                    let Some(role) =
+                        chat.roles
+                            .permitted(payload.author, &dependencies, &payload.content)
+                    else {
+                        tracing::warn!(
+                            ?chat.id,
+                            author = ?payload.author,
+                            role = ?chat.roles.role_at(payload.author, &dependencies),
+                            "rejecting chat message not allowed by the author's role"
+                        );
+                        return Ok(());
+                    };
+                    let message = ChatMessage::new(msg.hash.into(), msg.author().into(), payload);
+                    if let ChatMessageContent::Edit { target, .. } = &message.content {
+                        if let Some(original) = chat.message(target) {
+                            if original.author != message.author {
+                                tracing::warn!(
+                                    ?chat.id,
+                                    ?target,
+                                    editor = ?message.author,
+                                    "rejecting edit of someone else's message"
+                                );
+                                return Ok(());
+                            }
+                        }
+                    }
+                    let (id, author) = (message.id, message.author);
+                    let received_before = chat.marker_position(MarkerKind::Received, author);
+                    let purge = match message.content {
+                        // Deletions apply however late they arrive
+                        ChatMessageContent::Delete { .. }
+                        | ChatMessageContent::Retention { .. }
+                        | ChatMessageContent::Moderation { .. } => {
+                            chat.insert_message(message, role)
+                        }
+                        ChatMessageContent::GroupInfo { .. } => {
+                            let purge = chat.insert_message(message, role);
+                            if chat.group_info_setting() == Some(id) {
+                                self.emit_group_info(chat);
+                            }
+                            purge
+                        }
+                        // Arrived after it should already have disappeared
+                        _ if chat.is_expired(message.timestamp, timestamp_now()) => {
+                            chat.discard(message.id)
+                        }
+                        _ => chat.insert_message(message, role),
+                    };
+                    let me = self.public_key();
+                    if author != me {
+                        // Acknowledge anything which landed in the timeline
+                        if chat.message(&id).is_some() {
+                            self.schedule_marker(chat, MarkerKind::Received, id).await;
+                        }
+                        // A read or received marker may confirm some of our messages
+                        let received = chat.marker_position(MarkerKind::Received, author);
+                        if let Some(up_to) = received.filter(|_| received > received_before) {
+                            let members = self.other_member_count(chat.id).await?;
+                            let ids = chat.own_messages_between(received_before, up_to);
+                            self.emit_delivery(chat, ids, members);
+                        }
+                    }
+                    for message_id in purge {
+                        self.purge_message(message_id)
+                            .await
+                            .ok_or_warn("failed to purge deleted message");
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(?chat.id, ?err, "undecodable chat message");
+                }
+            },
             Event::Removed { .. } => {
                 tracing::warn!(?chat.id, "removed from chat");
                 chat.removed = true;
@@ -1,15 +1,21 @@
-use p2panda_core::cbor::{DecodeError, EncodeError, decode_cbor, encode_cbor};
+use p2panda_core::cbor::{DecodeError, EncodeError};
 use p2panda_core::{Body, Extension, PruneFlag};
 use serde::{Deserialize, Serialize};
 
+use crate::blobs::BlobMessage;
 use crate::chat::ChatId;
+use crate::invite::InviteToken;
 use crate::network::LogId;
-use crate::spaces::SpaceControlMessage;
+use crate::spaces::{MemberCode, SpaceControlMessage};
 use crate::{AsBody, Cbor};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Extensions {
     pub log_id: LogId,
+    /// Whether every earlier operation in the log may be deleted, because
+    /// this one carries whatever of them is still needed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prune: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -17,6 +23,15 @@ pub enum InvitationMessage {
     /// Instructs the recipient to subscribe to the group chat topic.
     JoinGroup(ChatId),
     Friend,
+    /// Tells the recipient they have been removed from a group chat. Only the
+    /// space itself can actually remove them.
+    RemovedFromGroup(ChatId),
+    /// Asks the recipient to add the sender to a chat they were invited to.
+    /// Carries the sender's member code, which adding them requires.
+    JoinRequest {
+        invite: InviteToken,
+        member: MemberCode,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -40,15 +55,47 @@ impl Extension<LogId> for Extensions {
 }
 
 impl Extension<PruneFlag> for Extensions {
-    fn extract(_header: &Header) -> Option<PruneFlag> {
-        Some(PruneFlag::new(false))
+    fn extract(header: &Header) -> Option<PruneFlag> {
+        Some(PruneFlag::new(
+            header
+                .extensions
+                .as_ref()
+                .is_some_and(|extensions| extensions.prune),
+        ))
     }
 }
 
+/// Everything sent on a topic's gossip overlay.
+///
+/// Each kind is tagged by name on the wire, so that a message is never
+/// mistaken for one of another kind.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipMessage {
+    /// The header and body bytes of an operation, which is also stored and
+    /// synced.
+    Operation(Vec<u8>, Option<Vec<u8>>),
+    /// A space message which is only seen by whoever is online, and never
+    /// stored.
+    Ephemeral(SpaceControlMessage),
+    /// A request for, or a piece of, a blob.
+    Blob(BlobMessage),
+}
+
+impl Cbor for GossipMessage {}
+
 pub fn encode_gossip_message(header: &Header, body: Option<&Body>) -> Result<Vec<u8>, EncodeError> {
-    encode_cbor(&(header.to_bytes(), body.map(|body| body.to_bytes())))
+    GossipMessage::Operation(header.to_bytes(), body.map(|body| body.to_bytes())).as_bytes()
+}
+
+pub fn encode_ephemeral_message(message: &SpaceControlMessage) -> Result<Vec<u8>, EncodeError> {
+    GossipMessage::Ephemeral(message.clone()).as_bytes()
+}
+
+pub fn encode_blob_message(message: &BlobMessage) -> Result<Vec<u8>, EncodeError> {
+    GossipMessage::Blob(message.clone()).as_bytes()
 }
 
-pub fn decode_gossip_message(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), DecodeError> {
-    decode_cbor(bytes)
+pub fn decode_gossip_message(bytes: &[u8]) -> Result<GossipMessage, DecodeError> {
+    GossipMessage::from_bytes(bytes)
 }
@@ -7,11 +7,20 @@ pub use store::*;
 use p2panda_spaces::manager::Manager;
 use p2panda_spaces::traits::SpaceId;
 use p2panda_spaces::types::StrongRemoveResolver;
+use serde::{Deserialize, Serialize};
 
 use crate::chat::ChatId;
 use crate::forge::DashForge;
 
-pub type TestConditions = ();
+/// Conditions attached to a member's access to a chat's Space.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct ChatConditions {
+    /// Manage access only extends to adding members, not to removing them
+    /// or changing their access.
+    pub add_only: bool,
+}
 
 impl SpaceId for ChatId {}
 
@@ -20,8 +29,8 @@ pub type DashSpace = p2panda_spaces::space::Space<
     SpacesStore,
     DashForge,
     SpaceControlMessage,
-    TestConditions,
-    StrongRemoveResolver<TestConditions>,
+    ChatConditions,
+    StrongRemoveResolver<ChatConditions>,
 >;
 
 pub type DashManager = Manager<
@@ -29,6 +38,6 @@ pub type DashManager = Manager<
     SpacesStore,
     DashForge,
     SpaceControlMessage,
-    TestConditions,
-    StrongRemoveResolver<TestConditions>,
+    ChatConditions,
+    StrongRemoveResolver<ChatConditions>,
 >;
@@ -2,7 +2,7 @@ use p2panda_core::cbor::{EncodeError, encode_cbor};
 use p2panda_spaces::OperationId;
 use serde::{Deserialize, Serialize};
 
-pub type SpacesArgs = p2panda_spaces::message::SpacesArgs<ChatId, ()>;
+pub type SpacesArgs = p2panda_spaces::message::SpacesArgs<ChatId, ChatConditions>;
 
 use super::*;
 
@@ -24,7 +24,7 @@ impl p2panda_spaces::message::AuthoredMessage for SpaceControlMessage {
     }
 }
 
-impl p2panda_spaces::message::SpacesMessage<ChatId, ()> for SpaceControlMessage {
+impl p2panda_spaces::message::SpacesMessage<ChatId, ChatConditions> for SpaceControlMessage {
     fn args(&self) -> &SpacesArgs {
         &self.spaces_args
     }
@@ -1,16 +1,13 @@
-use std::{str::FromStr, sync::Arc};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
 use p2panda_auth::traits::Conditions;
-use p2panda_core::{
-    PrivateKey,
-    cbor::{decode_cbor, encode_cbor},
-};
+use p2panda_core::cbor::{decode_cbor, encode_cbor};
 use p2panda_encryption::{
     Rng,
     crypto::x25519::SecretKey,
     key_bundle::{Lifetime, LongTermKeyBundle},
     key_manager::{KeyManager, KeyManagerState},
-    key_registry::KeyRegistryState,
+    key_registry::{KeyRegistry, KeyRegistryState},
     traits::PreKeyManager,
 };
 use p2panda_spaces::{
@@ -24,31 +21,127 @@ use p2panda_spaces::{
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use super::*;
+use crate::PK;
+use crate::db::{Database, DbError};
 
-pub type TestStore =
-    p2panda_spaces::test_utils::MemoryStore<ChatId, SpaceControlMessage, TestConditions>;
+use super::*;
 
-pub fn create_test_store(private_key: PrivateKey) -> TestStore {
+fn init_key_manager() -> KeyManagerState {
     let rng = Rng::default();
+    let identity_secret = SecretKey::from_bytes(rng.random_array().unwrap());
+    KeyManager::init(&identity_secret, Lifetime::default(), &rng).unwrap()
+}
 
-    let my_id: ActorId = private_key.public_key().into();
-
-    let key_manager_y = {
-        let identity_secret = SecretKey::from_bytes(rng.random_array().unwrap());
-        KeyManager::init(&identity_secret, Lifetime::default(), &rng).unwrap()
-    };
+/////////////////////////////////////////////////////////
 
-    let orderer_y = AuthOrderer::init();
-    let auth_y = AuthGroupState::new(orderer_y);
-    let store = TestStore::new(my_id, key_manager_y, auth_y);
+pub type SpacesStore = SharedSpaceStore<DashSpacesStore>;
+
+const KV_SPACES: &str = "spaces";
+const KV_SPACE_STATES: &str = "spaces/space";
+const KV_SPACE_MESSAGES: &str = "spaces/message";
+
+const KEY_MANAGER: &str = "key_manager";
+const KEY_REGISTRY: &str = "key_registry";
+const AUTH: &str = "auth";
+
+/// The spaces store used by the node.
+///
+/// All state is held in memory, and when a database is present every write
+/// is also persisted there, so that our key material, auth state and space
+/// states survive restarts. Application messages are the exception: nothing
+/// depends on them, and their ciphertext mustn't outlive their deletion, so
+/// they are only kept in memory until purged.
+pub struct DashSpacesStore {
+    key_manager: KeyManagerState,
+    key_registry: KeyRegistryState<ActorId>,
+    auth: AuthGroupState<ChatConditions>,
+    spaces: HashMap<ChatId, SpaceState<ChatId, SpaceControlMessage, ChatConditions>>,
+    messages: HashMap<OperationId, SpaceControlMessage>,
+    db: Option<Database>,
+}
 
-    store
+impl std::fmt::Debug for DashSpacesStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DashSpacesStore")
+            .field("spaces", &self.spaces.keys().collect::<Vec<_>>())
+            .field("messages", &self.messages.len())
+            .field("persisted", &self.db.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
-/////////////////////////////////////////////////////////
+impl DashSpacesStore {
+    /// A store which is never persisted.
+    pub fn memory() -> Self {
+        Self {
+            key_manager: init_key_manager(),
+            key_registry: KeyRegistry::<ActorId>::init(),
+            auth: AuthGroupState::new(AuthOrderer::init()),
+            spaces: HashMap::new(),
+            messages: HashMap::new(),
+            db: None,
+        }
+    }
+
+    /// Load the store from the database, creating and saving a fresh key
+    /// manager on first use.
+    pub fn load(db: Database) -> Result<Self, DbError> {
+        let key_manager = match db.get(KV_SPACES, &KEY_MANAGER)? {
+            Some(y) => y,
+            None => {
+                tracing::info!("no key manager found, creating new key material");
+                let y = init_key_manager();
+                db.put(KV_SPACES, &KEY_MANAGER, &y)?;
+                y
+            }
+        };
+
+        let mut messages = HashMap::new();
+        for (id, message) in db.list::<OperationId, SpaceControlMessage>(KV_SPACE_MESSAGES)? {
+            // Stored by earlier versions
+            if message.arg_type() == ArgType::Application {
+                db.remove(KV_SPACE_MESSAGES, &id)?;
+            } else {
+                messages.insert(id, message);
+            }
+        }
+
+        Ok(Self {
+            key_manager,
+            key_registry: db
+                .get(KV_SPACES, &KEY_REGISTRY)?
+                .unwrap_or_else(KeyRegistry::<ActorId>::init),
+            auth: db
+                .get(KV_SPACES, &AUTH)?
+                .unwrap_or_else(|| AuthGroupState::new(AuthOrderer::init())),
+            spaces: db.list(KV_SPACE_STATES)?.into_iter().collect(),
+            messages,
+            db: Some(db),
+        })
+    }
 
-pub type SpacesStore = SharedSpaceStore<TestStore>;
+    /// Forget a message which has been deleted or has expired, so that its
+    /// ciphertext isn't kept after its payload is purged.
+    pub fn remove_message(&mut self, id: &OperationId) -> Result<(), DbError> {
+        self.messages.remove(id);
+        if let Some(db) = &self.db {
+            db.remove(KV_SPACE_MESSAGES, id)?;
+        }
+        Ok(())
+    }
+
+    fn persist<K: Serialize, V: Serialize>(
+        &self,
+        namespace: &str,
+        key: &K,
+        value: &V,
+    ) -> Result<(), DbError> {
+        match &self.db {
+            Some(db) => db.put(namespace, key, value),
+            None => Ok(()),
+        }
+    }
+}
 
 #[derive(Debug, derive_more::Deref)]
 pub struct SharedSpaceStore<S>(Arc<RwLock<S>>);
@@ -77,6 +170,21 @@ impl<S: KeyStore> SharedSpaceStore<S> {
 #[serde(into = "String", try_from = "String")]
 pub struct MemberCode(LongTermKeyBundle, ActorId);
 
+impl MemberCode {
+    pub fn public_key(&self) -> PK {
+        self.1.into()
+    }
+}
+
+// Key bundles don't compare, so their encodings are compared instead
+impl PartialEq for MemberCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.1 == other.1 && encode_cbor(&self.0).ok() == encode_cbor(&other.0).ok()
+    }
+}
+
+impl Eq for MemberCode {}
+
 impl From<Member> for MemberCode {
     fn from(member: Member) -> Self {
         Self(member.key_bundle().clone(), member.id())
@@ -200,3 +308,92 @@ where
         self.write().await.set_message(id, message).await
     }
 }
+
+/////////////////////////////////////////////////////////////////
+
+impl SpaceStore<ChatId, SpaceControlMessage, ChatConditions> for DashSpacesStore {
+    type Error = DbError;
+
+    async fn space(
+        &self,
+        id: &ChatId,
+    ) -> Result<Option<SpaceState<ChatId, SpaceControlMessage, ChatConditions>>, Self::Error> {
+        Ok(self.spaces.get(id).cloned())
+    }
+
+    async fn has_space(&self, id: &ChatId) -> Result<bool, Self::Error> {
+        Ok(self.spaces.contains_key(id))
+    }
+
+    async fn spaces_ids(&self) -> Result<Vec<ChatId>, Self::Error> {
+        Ok(self.spaces.keys().copied().collect())
+    }
+
+    async fn set_space(
+        &mut self,
+        id: &ChatId,
+        y: SpaceState<ChatId, SpaceControlMessage, ChatConditions>,
+    ) -> Result<(), Self::Error> {
+        self.persist(KV_SPACE_STATES, id, &y)?;
+        self.spaces.insert(*id, y);
+        Ok(())
+    }
+}
+
+impl KeyStore for DashSpacesStore {
+    type Error = DbError;
+
+    async fn key_manager(&self) -> Result<KeyManagerState, Self::Error> {
+        Ok(self.key_manager.clone())
+    }
+
+    async fn key_registry(&self) -> Result<KeyRegistryState<ActorId>, Self::Error> {
+        Ok(self.key_registry.clone())
+    }
+
+    async fn set_key_manager(&mut self, y: &KeyManagerState) -> Result<(), Self::Error> {
+        self.persist(KV_SPACES, &KEY_MANAGER, y)?;
+        self.key_manager = y.clone();
+        Ok(())
+    }
+
+    async fn set_key_registry(&mut self, y: &KeyRegistryState<ActorId>) -> Result<(), Self::Error> {
+        self.persist(KV_SPACES, &KEY_REGISTRY, y)?;
+        self.key_registry = y.clone();
+        Ok(())
+    }
+}
+
+impl AuthStore<ChatConditions> for DashSpacesStore {
+    type Error = DbError;
+
+    async fn auth(&self) -> Result<AuthGroupState<ChatConditions>, Self::Error> {
+        Ok(self.auth.clone())
+    }
+
+    async fn set_auth(&mut self, y: &AuthGroupState<ChatConditions>) -> Result<(), Self::Error> {
+        self.persist(KV_SPACES, &AUTH, y)?;
+        self.auth = y.clone();
+        Ok(())
+    }
+}
+
+impl MessageStore<SpaceControlMessage> for DashSpacesStore {
+    type Error = DbError;
+
+    async fn message(&self, id: &OperationId) -> Result<Option<SpaceControlMessage>, Self::Error> {
+        Ok(self.messages.get(id).cloned())
+    }
+
+    async fn set_message(
+        &mut self,
+        id: &OperationId,
+        message: &SpaceControlMessage,
+    ) -> Result<(), Self::Error> {
+        if message.arg_type() != ArgType::Application {
+            self.persist(KV_SPACE_MESSAGES, id, message)?;
+        }
+        self.messages.insert(id.clone(), message.clone());
+        Ok(())
+    }
+}
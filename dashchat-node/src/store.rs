@@ -1,33 +1,89 @@
+mod sqlite;
+
+#[cfg(test)]
+mod tests;
+
+pub use sqlite::SqliteStore;
+
 use std::{collections::HashSet, convert::Infallible};
 
-use p2panda_core::{Body, Hash, Header, PublicKey, RawOperation};
+use p2panda_core::{
+    Body, Hash, Header, PublicKey, RawOperation,
+    cbor::{DecodeError, EncodeError},
+};
 use p2panda_store::{LogStore, MemoryStore, OperationStore};
 
 use crate::{
+    keyring::DecryptionError,
     network::{LogId, Topic},
     operation::Extensions,
+    util::ResultExt,
     *,
 };
 
-#[derive(Clone, Debug, derive_more::From, derive_more::Deref, derive_more::DerefMut)]
-pub struct OpStore(MemoryStore<LogId, Extensions>);
+/// The store for all p2panda operations, either kept in memory or persisted
+/// to disk.
+#[derive(Clone, Debug, derive_more::From)]
+pub enum OpStore {
+    Memory(MemoryStore<LogId, Extensions>),
+    Sqlite(SqliteStore),
+}
+
+#[derive(Debug, derive_more::Display, derive_more::Error, derive_more::From)]
+pub enum OpStoreError {
+    #[display("database error: {_0}")]
+    Database(rusqlite::Error),
+    #[display("encode error: {_0}")]
+    Encode(EncodeError),
+    #[display("decode error: {_0}")]
+    Decode(DecodeError),
+    #[display("{_0}")]
+    Decrypt(DecryptionError),
+    #[display("operation has no log id: {_0}")]
+    #[from(ignore)]
+    #[error(not(source))]
+    MissingLogId(Hash),
+}
+
+impl From<Infallible> for OpStoreError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
 
 impl OpStore {
+    /// All stored operations, optionally restricted to a set of topics.
+    pub fn operations<'a>(
+        &self,
+        topics: impl IntoIterator<Item = &'a Topic>,
+    ) -> Result<Vec<(Hash, Topic, Header<Extensions>, Option<Body>)>, OpStoreError> {
+        let topics = topics.into_iter().collect::<HashSet<_>>();
+        match self {
+            OpStore::Memory(store) => Ok(store
+                .read_store()
+                .operations
+                .iter()
+                .filter(|(_, (t, _, _, _))| topics.is_empty() || topics.contains(t))
+                .map(|(h, (t, header, body, _))| (*h, *t, header.clone(), body.clone()))
+                .collect()),
+            OpStore::Sqlite(store) => Ok(store
+                .operations()?
+                .into_iter()
+                .filter(|(_, t, _, _)| topics.is_empty() || topics.contains(t))
+                .collect()),
+        }
+    }
+
     pub fn report<'a>(&self, topics: impl IntoIterator<Item = &'a Topic>) -> String {
         let topics = topics.into_iter().collect::<HashSet<_>>();
-        let s = self.0.read_store();
-        let mut ops = s
-            .operations
-            .iter()
-            .filter(|(_, (t, _, _, _))| topics.is_empty() || topics.contains(t))
-            .collect::<Vec<_>>();
-        ops.sort_by_key(|(_, (t, header, _, _))| (t, header.public_key.short(), header.seq_num));
+        let mut ops = self
+            .operations(topics.iter().copied())
+            .ok_or_warn("failed to read operations for report")
+            .unwrap_or_default();
+        ops.sort_by_key(|(_, t, header, _)| (*t, header.public_key.short(), header.seq_num));
         ops.into_iter()
-            .map(|(h, (t, header, body, _))| {
-                let desc = match body
-                    .clone()
-                    .map(|body| Payload::try_from_body(body).unwrap())
-                {
+            .map(|(h, t, header, body)| {
+                let desc = match body.map(|body| Payload::try_from_body(body).unwrap()) {
                     Some(Payload::SpaceControl(msgs)) => {
                         format!(
                             "{:?}",
@@ -63,7 +119,7 @@ impl OpStore {
 }
 
 impl OperationStore<LogId, Extensions> for OpStore {
-    type Error = Infallible;
+    type Error = OpStoreError;
 
     async fn insert_operation(
         &mut self,
@@ -73,37 +129,57 @@ impl OperationStore<LogId, Extensions> for OpStore {
         header_bytes: &[u8],
         log_id: &LogId,
     ) -> Result<bool, Self::Error> {
-        self.0
-            .insert_operation(hash, header, body, header_bytes, log_id)
-            .await
+        match self {
+            OpStore::Memory(store) => Ok(store
+                .insert_operation(hash, header, body, header_bytes, log_id)
+                .await?),
+            OpStore::Sqlite(store) => {
+                store.insert_operation(hash, header, body, header_bytes, log_id)
+            }
+        }
     }
 
     async fn get_operation(
         &self,
         hash: Hash,
     ) -> Result<Option<(Header<Extensions>, Option<Body>)>, Self::Error> {
-        self.0.get_operation(hash).await
+        match self {
+            OpStore::Memory(store) => Ok(store.get_operation(hash).await?),
+            OpStore::Sqlite(store) => store.get_operation(hash),
+        }
     }
 
     async fn get_raw_operation(&self, hash: Hash) -> Result<Option<RawOperation>, Self::Error> {
-        self.0.get_raw_operation(hash).await
+        match self {
+            OpStore::Memory(store) => Ok(store.get_raw_operation(hash).await?),
+            OpStore::Sqlite(store) => store.get_raw_operation(hash),
+        }
     }
 
     async fn has_operation(&self, hash: Hash) -> Result<bool, Self::Error> {
-        self.0.has_operation(hash).await
+        match self {
+            OpStore::Memory(store) => Ok(store.has_operation(hash).await?),
+            OpStore::Sqlite(store) => store.has_operation(hash),
+        }
     }
 
     async fn delete_operation(&mut self, hash: Hash) -> Result<bool, Self::Error> {
-        self.0.delete_operation(hash).await
+        match self {
+            OpStore::Memory(store) => Ok(store.delete_operation(hash).await?),
+            OpStore::Sqlite(store) => store.delete_operation(hash),
+        }
     }
 
     async fn delete_payload(&mut self, hash: Hash) -> Result<bool, Self::Error> {
-        self.0.delete_payload(hash).await
+        match self {
+            OpStore::Memory(store) => Ok(store.delete_payload(hash).await?),
+            OpStore::Sqlite(store) => store.delete_payload(hash),
+        }
     }
 }
 
 impl LogStore<LogId, Extensions> for OpStore {
-    type Error = Infallible;
+    type Error = OpStoreError;
 
     async fn get_log(
         &self,
@@ -111,7 +187,10 @@ impl LogStore<LogId, Extensions> for OpStore {
         log_id: &LogId,
         from: Option<u64>,
     ) -> Result<Option<Vec<(Header<Extensions>, Option<Body>)>>, Self::Error> {
-        self.0.get_log(public_key, log_id, from).await
+        match self {
+            OpStore::Memory(store) => Ok(store.get_log(public_key, log_id, from).await?),
+            OpStore::Sqlite(store) => store.get_log(public_key, log_id, from),
+        }
     }
 
     async fn get_raw_log(
@@ -120,7 +199,10 @@ impl LogStore<LogId, Extensions> for OpStore {
         log_id: &LogId,
         from: Option<u64>,
     ) -> Result<Option<Vec<RawOperation>>, Self::Error> {
-        self.0.get_raw_log(public_key, log_id, from).await
+        match self {
+            OpStore::Memory(store) => Ok(store.get_raw_log(public_key, log_id, from).await?),
+            OpStore::Sqlite(store) => store.get_raw_log(public_key, log_id, from),
+        }
     }
 
     async fn latest_operation(
@@ -128,11 +210,17 @@ impl LogStore<LogId, Extensions> for OpStore {
         public_key: &PublicKey,
         log_id: &LogId,
     ) -> Result<Option<(Header<Extensions>, Option<Body>)>, Self::Error> {
-        self.0.latest_operation(public_key, log_id).await
+        match self {
+            OpStore::Memory(store) => Ok(store.latest_operation(public_key, log_id).await?),
+            OpStore::Sqlite(store) => store.latest_operation(public_key, log_id),
+        }
     }
 
     async fn get_log_heights(&self, log_id: &LogId) -> Result<Vec<(PublicKey, u64)>, Self::Error> {
-        self.0.get_log_heights(log_id).await
+        match self {
+            OpStore::Memory(store) => Ok(store.get_log_heights(log_id).await?),
+            OpStore::Sqlite(store) => store.get_log_heights(log_id),
+        }
     }
 
     async fn delete_operations(
@@ -141,7 +229,12 @@ impl LogStore<LogId, Extensions> for OpStore {
         log_id: &LogId,
         before: u64,
     ) -> Result<bool, Self::Error> {
-        self.0.delete_operations(public_key, log_id, before).await
+        match self {
+            OpStore::Memory(store) => {
+                Ok(store.delete_operations(public_key, log_id, before).await?)
+            }
+            OpStore::Sqlite(store) => store.delete_operations(public_key, log_id, before),
+        }
     }
 
     async fn delete_payloads(
@@ -151,6 +244,11 @@ impl LogStore<LogId, Extensions> for OpStore {
         from: u64,
         to: u64,
     ) -> Result<bool, Self::Error> {
-        self.0.delete_payloads(public_key, log_id, from, to).await
+        match self {
+            OpStore::Memory(store) => {
+                Ok(store.delete_payloads(public_key, log_id, from, to).await?)
+            }
+            OpStore::Sqlite(store) => store.delete_payloads(public_key, log_id, from, to),
+        }
     }
 }
@@ -0,0 +1,270 @@
+use p2panda_core::{
+    Body, Hash, Header, PublicKey, RawOperation,
+    cbor::{decode_cbor, encode_cbor},
+};
+use rusqlite::{OptionalExtension, params};
+
+use crate::{db::Database, network::LogId, operation::Extensions};
+
+use super::OpStoreError;
+
+/// An operation store backed by the node's SQLite database.
+///
+/// Headers are stored as their original signed bytes and decoded on read,
+/// so raw operations handed to sync are byte-for-byte what was ingested.
+/// Header and body bytes are encrypted at rest, and the log columns hold
+/// keyed hashes of the author and log id (see [`Database::lookup_key`]).
+/// Both can be read back from the header when needed.
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    db: Database,
+}
+
+type Operation = (Header<Extensions>, Option<Body>);
+
+impl SqliteStore {
+    pub fn new(db: Database) -> Self {
+        Self { db }
+    }
+
+    fn public_key_column(&self, public_key: &PublicKey) -> Vec<u8> {
+        self.db.lookup_key("public_key", public_key.as_bytes())
+    }
+
+    fn log_id_column(&self, log_id: &LogId) -> Result<Vec<u8>, OpStoreError> {
+        Ok(self.db.lookup_key("log_id", &encode_cbor(log_id)?))
+    }
+
+    fn unseal(&self, (header, body): RawOperation) -> Result<RawOperation, OpStoreError> {
+        Ok((
+            self.db.unseal(&header)?,
+            body.map(|body| self.db.unseal(&body)).transpose()?,
+        ))
+    }
+
+    /// All operations in the store along with the log they belong to.
+    pub fn operations(
+        &self,
+    ) -> Result<Vec<(Hash, LogId, Header<Extensions>, Option<Body>)>, OpStoreError> {
+        let conn = self.db.conn();
+        let mut stmt = conn.prepare("SELECT header, body FROM operations")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|raw| {
+                let (header, body) = decode_operation(self.unseal(raw)?)?;
+                let log_id = header
+                    .extension()
+                    .ok_or(OpStoreError::MissingLogId(header.hash()))?;
+                Ok((header.hash(), log_id, header, body))
+            })
+            .collect()
+    }
+
+    pub(super) fn insert_operation(
+        &self,
+        hash: Hash,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+        header_bytes: &[u8],
+        log_id: &LogId,
+    ) -> Result<bool, OpStoreError> {
+        let inserted = self.db.conn().execute(
+            "INSERT OR IGNORE INTO operations (hash, log_id, public_key, seq_num, header, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                hash.as_bytes().as_slice(),
+                self.log_id_column(log_id)?,
+                self.public_key_column(&header.public_key),
+                header.seq_num as i64,
+                self.db.seal(header_bytes),
+                body.map(|body| self.db.seal(&body.to_bytes())),
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    pub(super) fn get_operation(&self, hash: Hash) -> Result<Option<Operation>, OpStoreError> {
+        self.get_raw_operation(hash)?
+            .map(decode_operation)
+            .transpose()
+    }
+
+    pub(super) fn get_raw_operation(
+        &self,
+        hash: Hash,
+    ) -> Result<Option<RawOperation>, OpStoreError> {
+        let raw = self
+            .db
+            .conn()
+            .query_row(
+                "SELECT header, body FROM operations WHERE hash = ?1",
+                params![hash.as_bytes().as_slice()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        raw.map(|raw| self.unseal(raw)).transpose()
+    }
+
+    pub(super) fn has_operation(&self, hash: Hash) -> Result<bool, OpStoreError> {
+        Ok(self.db.conn().query_row(
+            "SELECT EXISTS (SELECT 1 FROM operations WHERE hash = ?1)",
+            params![hash.as_bytes().as_slice()],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub(super) fn delete_operation(&self, hash: Hash) -> Result<bool, OpStoreError> {
+        let deleted = self.db.conn().execute(
+            "DELETE FROM operations WHERE hash = ?1",
+            params![hash.as_bytes().as_slice()],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    pub(super) fn delete_payload(&self, hash: Hash) -> Result<bool, OpStoreError> {
+        let updated = self.db.conn().execute(
+            "UPDATE operations SET body = NULL WHERE hash = ?1 AND body IS NOT NULL",
+            params![hash.as_bytes().as_slice()],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub(super) fn get_log(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        from: Option<u64>,
+    ) -> Result<Option<Vec<Operation>>, OpStoreError> {
+        self.get_raw_log(public_key, log_id, from)?
+            .map(|log| log.into_iter().map(decode_operation).collect())
+            .transpose()
+    }
+
+    pub(super) fn get_raw_log(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        from: Option<u64>,
+    ) -> Result<Option<Vec<RawOperation>>, OpStoreError> {
+        let conn = self.db.conn();
+        let public_key = self.public_key_column(public_key);
+        let log_id = self.log_id_column(log_id)?;
+
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM operations WHERE public_key = ?1 AND log_id = ?2)",
+            params![public_key, log_id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(None);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT header, body FROM operations
+             WHERE public_key = ?1 AND log_id = ?2 AND seq_num >= ?3
+             ORDER BY seq_num ASC",
+        )?;
+        let log = stmt
+            .query_map(
+                params![public_key, log_id, from.unwrap_or(0) as i64],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        log.into_iter()
+            .map(|raw| self.unseal(raw))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    pub(super) fn latest_operation(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+    ) -> Result<Option<Operation>, OpStoreError> {
+        let raw: Option<RawOperation> = self
+            .db
+            .conn()
+            .query_row(
+                "SELECT header, body FROM operations
+                 WHERE public_key = ?1 AND log_id = ?2
+                 ORDER BY seq_num DESC LIMIT 1",
+                params![
+                    self.public_key_column(public_key),
+                    self.log_id_column(log_id)?
+                ],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        raw.map(|raw| decode_operation(self.unseal(raw)?))
+            .transpose()
+    }
+
+    pub(super) fn get_log_heights(
+        &self,
+        log_id: &LogId,
+    ) -> Result<Vec<(PublicKey, u64)>, OpStoreError> {
+        let conn = self.db.conn();
+        let mut stmt = conn.prepare(
+            // SQLite takes the bare header column from the row with the
+            // highest sequence number, which is where we read the author from.
+            "SELECT header, MAX(seq_num) FROM operations
+             WHERE log_id = ?1 GROUP BY public_key",
+        )?;
+        let rows = stmt
+            .query_map(params![self.log_id_column(log_id)?], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(header, seq_num)| {
+                let header: Header<Extensions> = decode_cbor(self.db.unseal(&header)?.as_slice())?;
+                Ok((header.public_key, seq_num as u64))
+            })
+            .collect()
+    }
+
+    pub(super) fn delete_operations(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        before: u64,
+    ) -> Result<bool, OpStoreError> {
+        let deleted = self.db.conn().execute(
+            "DELETE FROM operations WHERE public_key = ?1 AND log_id = ?2 AND seq_num < ?3",
+            params![
+                self.public_key_column(public_key),
+                self.log_id_column(log_id)?,
+                before as i64
+            ],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    pub(super) fn delete_payloads(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        from: u64,
+        to: u64,
+    ) -> Result<bool, OpStoreError> {
+        let updated = self.db.conn().execute(
+            "UPDATE operations SET body = NULL
+             WHERE public_key = ?1 AND log_id = ?2 AND seq_num >= ?3 AND seq_num < ?4
+             AND body IS NOT NULL",
+            params![
+                self.public_key_column(public_key),
+                self.log_id_column(log_id)?,
+                from as i64,
+                to as i64
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+}
+
+fn decode_operation((header, body): RawOperation) -> Result<Operation, OpStoreError> {
+    let header: Header<Extensions> = decode_cbor(header.as_slice())?;
+    Ok((header, body.map(|body| Body::new(&body))))
+}
@@ -0,0 +1,109 @@
+use p2panda_core::{Body, Header, PrivateKey};
+use p2panda_store::{LogStore, OperationStore};
+
+use crate::{chat::ChatId, db::Database, keyring::DataKey, network::Topic, operation::Extensions};
+
+use super::*;
+
+fn operation(
+    private_key: &PrivateKey,
+    topic: Topic,
+    seq_num: u64,
+    backlink: Option<Hash>,
+) -> (Header<Extensions>, Body) {
+    let body = Body::new(format!("op {seq_num}").as_bytes());
+    let mut header = Header {
+        version: 1,
+        public_key: private_key.public_key(),
+        signature: None,
+        payload_size: body.size(),
+        payload_hash: Some(body.hash()),
+        timestamp: seq_num,
+        seq_num,
+        backlink,
+        previous: vec![],
+        extensions: Some(Extensions {
+            log_id: topic,
+            prune: false,
+        }),
+    };
+    header.sign(private_key);
+    (header, body)
+}
+
+#[tokio::test]
+async fn test_sqlite_store_survives_reopen() {
+    let path = std::env::temp_dir().join(format!("dashchat-{}.sqlite", ChatId::random()));
+    let private_key = PrivateKey::new();
+    let public_key = private_key.public_key();
+    let topic = Topic::Chat(ChatId::random());
+    let data_key = DataKey::random();
+
+    let mut store = OpStore::from(SqliteStore::new(
+        Database::open(&path, data_key.clone()).unwrap(),
+    ));
+
+    let mut backlink = None;
+    for seq_num in 0..3 {
+        let (header, body) = operation(&private_key, topic, seq_num, backlink);
+        let inserted = store
+            .insert_operation(
+                header.hash(),
+                &header,
+                Some(&body),
+                &header.to_bytes(),
+                &topic,
+            )
+            .await
+            .unwrap();
+        assert!(inserted);
+        backlink = Some(header.hash());
+    }
+    drop(store);
+
+    // Authors are only stored as keyed hashes and inside sealed headers.
+    let raw = std::fs::read(&path).unwrap();
+    assert!(!raw.windows(32).any(|w| w == public_key.as_bytes()));
+
+    let mut store = OpStore::from(SqliteStore::new(Database::open(&path, data_key).unwrap()));
+
+    let log = store
+        .get_log(&public_key, &topic, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(log.len(), 3);
+    assert_eq!(
+        store.get_log_heights(&topic).await.unwrap(),
+        vec![(public_key, 2)]
+    );
+    assert_eq!(
+        store
+            .get_log(&public_key, &topic, Some(1))
+            .await
+            .unwrap()
+            .map(|log| log.len()),
+        Some(2)
+    );
+
+    let (latest, _) = store
+        .latest_operation(&public_key, &topic)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some(latest.hash()), backlink);
+
+    assert!(store.delete_payload(latest.hash()).await.unwrap());
+    let (_, body) = store.get_operation(latest.hash()).await.unwrap().unwrap();
+    assert!(body.is_none());
+
+    assert!(
+        store
+            .delete_operations(&public_key, &topic, 2)
+            .await
+            .unwrap()
+    );
+    assert_eq!(store.operations(&[topic]).unwrap().len(), 1);
+
+    std::fs::remove_file(&path).ok();
+}
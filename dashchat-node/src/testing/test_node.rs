@@ -22,6 +22,12 @@ impl TestNode {
         );
         (node, Watcher(notification_rx))
     }
+
+    pub async fn with_config(config: NodeConfig) -> (Self, Watcher<Notification>) {
+        let (notification_tx, notification_rx) = tokio::sync::mpsc::channel(100);
+        let node = Self(Node::open(config, Some(notification_tx)).await.unwrap());
+        (node, Watcher(notification_rx))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -92,16 +98,10 @@ pub async fn consistency(
             .iter()
             .map(|node| {
                 node.op_store
-                    .read_store()
-                    .operations
-                    .iter()
-                    .filter_map(|(h, (t, _, _, _))| {
-                        if topics.is_empty() || topics.contains(t) {
-                            Some(h.short())
-                        } else {
-                            None
-                        }
-                    })
+                    .operations(topics.iter().copied())
+                    .unwrap()
+                    .into_iter()
+                    .map(|(h, _, _, _)| h.short())
                     .collect::<BTreeSet<_>>()
             })
             .collect::<Vec<_>>();
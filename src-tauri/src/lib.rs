@@ -1,9 +1,10 @@
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use dashchat_node::*;
 use serde::{Deserialize, Serialize};
-use tauri::{Manager, State};
+use tauri::{Emitter, Manager, State};
 use tauri_plugin_log::log::{Level, LevelFilter};
+use tokio::sync::broadcast::error::RecvError;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -21,10 +22,20 @@ async fn me(node: State<'_, Node>) -> Result<MemberCode, String> {
 
 #[tauri::command]
 async fn create_group(name: &str, node: State<'_, Node>) -> Result<ChatId, String> {
-    match node.create_group().await {
-        Ok((chat_id, _)) => Ok(chat_id),
-        Err(err) => Err(format!("Error sending message: {err:?}")),
+    let chat_id = match node.create_group().await {
+        Ok((chat_id, _)) => chat_id,
+        Err(err) => return Err(format!("Error sending message: {err:?}")),
+    };
+    if !name.is_empty() {
+        let info = GroupInfo {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        if let Err(err) = node.set_group_info(chat_id, info).await {
+            return Err(format!("Error naming group: {err:?}"));
+        }
     }
+    Ok(chat_id)
 }
 
 #[tauri::command]
@@ -41,9 +52,14 @@ async fn get_groups(node: State<'_, Node>) -> Result<Vec<ChatOverview>, String>
     let chat_ids = node.get_groups().await.map_err(|e| e.to_string())?;
 
     for chat_id in chat_ids {
+        let info = node.group_info(chat_id).await.map_err(|e| e.to_string())?;
         let overview = ChatOverview {
             chat_id,
-            name: chat_id.to_string(),
+            name: if info.name.is_empty() {
+                chat_id.to_string()
+            } else {
+                info.name
+            },
             member_count: node
                 .get_members(chat_id)
                 .await
@@ -57,19 +73,86 @@ async fn get_groups(node: State<'_, Node>) -> Result<Vec<ChatOverview>, String>
 }
 
 #[tauri::command]
-async fn add_member(chat_id: ChatId, pubkey: PK, node: State<'_, Node>) -> Result<(), String> {
-    match node.add_member(chat_id, pubkey).await {
+async fn leave_group(chat_id: ChatId, node: State<'_, Node>) -> Result<(), String> {
+    match node.leave_group(chat_id).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Error leaving group: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn add_member(
+    chat_id: ChatId,
+    pubkey: PK,
+    role: Role,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.add_member(chat_id, pubkey, role).await {
         Ok(_) => Ok(()),
         Err(err) => Err(format!("Error adding member: {err:?}")),
     }
 }
 
 #[tauri::command]
-async fn get_members(chat_id: ChatId, node: State<'_, Node>) -> Result<Vec<PK>, String> {
+async fn create_invite(
+    chat_id: ChatId,
+    role: Role,
+    lifetime_secs: u64,
+    single_use: bool,
+    node: State<'_, Node>,
+) -> Result<String, String> {
+    match node
+        .create_invite(
+            chat_id,
+            role,
+            Duration::from_secs(lifetime_secs),
+            single_use,
+        )
+        .await
+    {
+        Ok(invite) => Ok(invite.to_string()),
+        Err(err) => Err(format!("Error creating invite: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn redeem_invite(invite: &str, node: State<'_, Node>) -> Result<ChatId, String> {
+    let invite = invite
+        .parse::<InviteToken>()
+        .map_err(|err| format!("Invalid invite: {err:?}"))?;
+    match node.redeem_invite(invite).await {
+        Ok(chat_id) => Ok(chat_id),
+        Err(err) => Err(format!("Error redeeming invite: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn remove_member(chat_id: ChatId, pubkey: PK, node: State<'_, Node>) -> Result<(), String> {
+    match node.remove_member(chat_id, pubkey).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error removing member: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn set_role(
+    chat_id: ChatId,
+    pubkey: PK,
+    role: Role,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.set_role(chat_id, pubkey, role).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error changing role: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_members(chat_id: ChatId, node: State<'_, Node>) -> Result<Vec<(PK, Role)>, String> {
     match node.get_members(chat_id).await {
         Ok(members) => Ok(members
             .into_iter()
-            .map(|(actor_id, _access)| PK::try_from(actor_id).unwrap())
+            .map(|(actor_id, access)| (PK::try_from(actor_id).unwrap(), Role::from_access(&access)))
             .collect()),
         Err(err) => Err(format!("Error getting participants: {err:?}")),
     }
@@ -88,6 +171,175 @@ async fn send_message(
     }
 }
 
+#[tauri::command]
+async fn send_reply(
+    chat_id: ChatId,
+    reply_to: MessageId,
+    message: String,
+    node: State<'_, Node>,
+) -> Result<ChatMessage, String> {
+    match node.send_reply(chat_id, reply_to, message).await {
+        Ok(message) => Ok(message),
+        Err(err) => Err(format!("Error sending reply: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_thread(
+    chat_id: ChatId,
+    root: MessageId,
+    node: State<'_, Node>,
+) -> Result<Vec<ChatMessage>, String> {
+    match node.get_thread(chat_id, root).await {
+        Ok(messages) => Ok(messages),
+        Err(err) => Err(format!("Failed to get thread: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn edit_message(
+    chat_id: ChatId,
+    message_id: MessageId,
+    message: String,
+    node: State<'_, Node>,
+) -> Result<ChatMessage, String> {
+    match node.edit_message(chat_id, message_id, message).await {
+        Ok(message) => Ok(message),
+        Err(err) => Err(format!("Error editing message: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_edit_history(
+    chat_id: ChatId,
+    message_id: MessageId,
+    node: State<'_, Node>,
+) -> Result<Vec<ChatMessage>, String> {
+    match node.get_edit_history(chat_id, message_id).await {
+        Ok(messages) => Ok(messages),
+        Err(err) => Err(format!("Failed to get edit history: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn delete_message(
+    chat_id: ChatId,
+    message_id: MessageId,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.delete_message(chat_id, message_id).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error deleting message: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn mark_read(
+    chat_id: ChatId,
+    message_id: MessageId,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.mark_read(chat_id, message_id).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error marking message as read: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn send_attachment(
+    chat_id: ChatId,
+    name: String,
+    mime_type: String,
+    data: Vec<u8>,
+    caption: Option<String>,
+    node: State<'_, Node>,
+) -> Result<ChatMessage, String> {
+    match node
+        .send_attachment(chat_id, name, mime_type, &data, caption)
+        .await
+    {
+        Ok(message) => Ok(message),
+        Err(err) => Err(format!("Error sending attachment: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn download_attachment(
+    chat_id: ChatId,
+    attachment: AttachmentRef,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.download_attachment(chat_id, &attachment).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error downloading attachment: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_attachment(
+    attachment: AttachmentRef,
+    node: State<'_, Node>,
+) -> Result<Option<Vec<u8>>, String> {
+    match node.get_attachment(&attachment).await {
+        Ok(data) => Ok(data),
+        Err(err) => Err(format!("Error getting attachment: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn send_signal(chat_id: ChatId, signal: Signal, node: State<'_, Node>) -> Result<(), String> {
+    match node.send_signal(chat_id, signal).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error sending signal: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_signals(chat_id: ChatId, node: State<'_, Node>) -> Result<Vec<SignalEvent>, String> {
+    match node.get_signals(chat_id).await {
+        Ok(signals) => Ok(signals),
+        Err(err) => Err(format!("Error getting signals: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_read_by(
+    chat_id: ChatId,
+    message_id: MessageId,
+    node: State<'_, Node>,
+) -> Result<Vec<PK>, String> {
+    match node.get_read_by(chat_id, message_id).await {
+        Ok(members) => Ok(members),
+        Err(err) => Err(format!("Failed to get read receipts: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn react(
+    chat_id: ChatId,
+    message_id: MessageId,
+    emoji: String,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.react(chat_id, message_id, emoji).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error reacting: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn unreact(
+    chat_id: ChatId,
+    message_id: MessageId,
+    emoji: String,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.unreact(chat_id, message_id, emoji).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Error removing reaction: {err:?}")),
+    }
+}
+
 #[tauri::command]
 async fn get_messages(chat_id: ChatId, node: State<'_, Node>) -> Result<Vec<ChatMessage>, String> {
     match node.get_messages(chat_id).await {
@@ -96,6 +348,108 @@ async fn get_messages(chat_id: ChatId, node: State<'_, Node>) -> Result<Vec<Chat
     }
 }
 
+#[tauri::command]
+async fn get_messages_page(
+    chat_id: ChatId,
+    cursor: MessageCursor,
+    limit: usize,
+    node: State<'_, Node>,
+) -> Result<Vec<ChatMessage>, String> {
+    match node.get_messages_page(chat_id, cursor, limit).await {
+        Ok(messages) => Ok(messages),
+        Err(err) => Err(format!("Error getting messages: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn search(
+    query: String,
+    filters: SearchFilters,
+    node: State<'_, Node>,
+) -> Result<Vec<SearchResult>, String> {
+    match node.search(&query, filters).await {
+        Ok(results) => Ok(results),
+        Err(err) => Err(format!("Error searching messages: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_group_info(chat_id: ChatId, node: State<'_, Node>) -> Result<GroupInfo, String> {
+    match node.group_info(chat_id).await {
+        Ok(info) => Ok(info),
+        Err(err) => Err(format!("Error getting group info: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn set_group_info(
+    chat_id: ChatId,
+    info: GroupInfo,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.set_group_info(chat_id, info).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Error setting group info: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn set_group_avatar(
+    chat_id: ChatId,
+    name: String,
+    mime_type: String,
+    data: Vec<u8>,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.set_group_avatar(chat_id, name, mime_type, &data).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Error setting group avatar: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn set_retention(
+    chat_id: ChatId,
+    lifetime_secs: Option<u64>,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node
+        .set_retention(chat_id, lifetime_secs.map(Duration::from_secs))
+        .await
+    {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Error setting retention: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_retention(chat_id: ChatId, node: State<'_, Node>) -> Result<Option<u64>, String> {
+    match node.get_retention(chat_id).await {
+        Ok(lifetime) => Ok(lifetime.map(|lifetime| lifetime.as_secs())),
+        Err(err) => Err(format!("Error getting retention: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn set_moderation(
+    chat_id: ChatId,
+    enabled: bool,
+    node: State<'_, Node>,
+) -> Result<(), String> {
+    match node.set_moderation(chat_id, enabled).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("Error setting moderation: {err:?}")),
+    }
+}
+
+#[tauri::command]
+async fn get_moderation(chat_id: ChatId, node: State<'_, Node>) -> Result<bool, String> {
+    match node.get_moderation(chat_id).await {
+        Ok(enabled) => Ok(enabled),
+        Err(err) => Err(format!("Error getting moderation: {err:?}")),
+    }
+}
+
 // Friend management commands
 #[tauri::command]
 async fn add_friend(friend_code: MemberCode, node: State<'_, Node>) -> Result<PK, String> {
@@ -121,6 +475,30 @@ async fn remove_friend(public_key: PK, node: State<'_, Node>) -> Result<(), Stri
     }
 }
 
+/// Emit everything received from a node's broadcast channel as `event` to
+/// the frontend.
+fn forward_events<T: Serialize + Clone + Send + 'static>(
+    handle: tauri::AppHandle,
+    event: &'static str,
+    mut updates: tokio::sync::broadcast::Receiver<T>,
+) {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match updates.recv().await {
+                Ok(update) => {
+                    if let Err(err) = handle.emit(event, update) {
+                        tracing::warn!("Error emitting {event} event: {err:?}");
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Skipped {skipped} {event} events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
@@ -137,24 +515,66 @@ pub fn run() {
             me,
             create_group,
             join_group,
+            leave_group,
             get_groups,
             add_member,
+            remove_member,
+            create_invite,
+            redeem_invite,
+            set_role,
             get_members,
             send_message,
+            send_reply,
+            edit_message,
+            get_edit_history,
+            delete_message,
+            mark_read,
+            get_read_by,
+            send_signal,
+            get_signals,
+            send_attachment,
+            download_attachment,
+            get_attachment,
+            react,
+            unreact,
             get_messages,
+            get_messages_page,
+            search,
+            set_retention,
+            get_retention,
+            set_moderation,
+            get_moderation,
+            get_group_info,
+            set_group_info,
+            set_group_avatar,
+            get_thread,
             add_friend,
             get_friends,
             remove_friend
         ])
         .setup(|app| {
             let handle = app.handle().clone();
+            let data_dir = app.path().app_data_dir()?;
             tauri::async_runtime::spawn(async move {
-                let private_key = dashchat_node::PrivateKey::new();
+                let mut config = dashchat_node::NodeConfig::with_data_dir(data_dir);
+                // There is no unlock screen yet, so a passphrase for the data
+                // directory can only be given through the environment.
+                if let Ok(passphrase) = std::env::var("DASHCHAT_PASSPHRASE") {
+                    config = config.with_passphrase(passphrase);
+                }
 
-                let node = Node::new(private_key, dashchat_node::NodeConfig::default(), None).await;
+                let node = Node::open(config, None).await;
 
                 match node {
                     Ok(node) => {
+                        forward_events(handle.clone(), "delivery", node.subscribe_delivery());
+                        forward_events(handle.clone(), "signal", node.subscribe_signals());
+                        forward_events(handle.clone(), "group_info", node.subscribe_group_info());
+                        forward_events(
+                            handle.clone(),
+                            "blob_progress",
+                            node.subscribe_blob_progress(),
+                        );
                         handle.manage(node);
                     }
                     Err(err) => {
@@ -7,17 +7,124 @@ export interface ChatOverview {
 }
 
 export interface ChatMessage {
-    content: string;
+    id: MessageId;
+    content: ChatMessageContent;
     author: PubKey; // 32-byte public key
     timestamp: number;
+    quoted: QuotedMessage | null;
+    reactions: Record<string, PubKey[]>;
+    edited: boolean;
+    delivery: DeliveryState | null;
+}
+
+export type DeliveryState =
+    | { state: "pending" }
+    | { state: "gossiped" }
+    | { state: "delivered"; received_by: number; members: number };
+
+export interface DeliveryUpdate {
+    chat_id: ChatId;
+    message_id: MessageId;
+    state: DeliveryState;
+}
+
+export interface QuotedMessage {
+    id: MessageId;
+    author: PubKey;
+    snippet: string;
+}
+
+export type ChatMessageContent =
+    | { type: "text"; text: string }
+    | { type: "reply"; reply_to: MessageId; text: string }
+    | {
+          type: "reaction";
+          target: MessageId;
+          emoji: string;
+          remove: boolean;
+          clock: number;
+      }
+    | { type: "edit"; target: MessageId; text: string; revision: number }
+    | { type: "delete"; target: MessageId; moderation: MessageId | null }
+    | { type: "read_marker"; up_to: MessageId }
+    | { type: "received_marker"; up_to: MessageId }
+    | { type: "attachment"; attachment: AttachmentRef; caption: string | null }
+    | { type: "retention"; lifetime: number | null }
+    | { type: "moderation"; enabled: boolean }
+    | { type: "group_info"; info: GroupInfo; version: number }
+    | { type: "system"; text: string }
+    | { type: "unknown" };
+
+export type Signal = { type: "typing" } | { type: "online" } | { type: "unknown" };
+
+export interface SignalEvent {
+    chat_id: ChatId;
+    author: PubKey;
+    signal: Signal;
+    expires_at: number;
+}
+
+export interface SearchFilters {
+    chat_id?: ChatId;
+    author?: PubKey;
+    since?: number;
+    until?: number;
+}
+
+export interface SnippetPart {
+    text: string;
+    highlight: boolean;
+}
+
+export interface SearchResult {
+    chat_id: ChatId;
+    message_id: MessageId;
+    author: PubKey;
+    timestamp: number;
+    snippet: SnippetPart[];
+}
+
+export interface AttachmentRef {
+    hash: number[];
+    key: number[];
+    name: string;
+    mime_type: string;
+    size: number;
+}
+
+export type MessageCursor =
+    | { type: "latest" }
+    | { type: "before"; message_id: MessageId }
+    | { type: "after"; message_id: MessageId }
+    | { type: "before_time"; timestamp: number }
+    | { type: "after_time"; timestamp: number };
+
+export interface BlobProgress {
+    hash: number[];
+    received: number;
+    total: number;
+}
+
+export interface GroupInfo {
+    name: string;
+    description: string;
+    avatar: AttachmentRef | null;
+}
+
+export interface GroupInfoUpdate {
+    chat_id: ChatId;
+    info: GroupInfo;
 }
 
 export interface Participant {
     publicKey: PubKey;
     name: string;
     avatar: string; // base64 dataUrl
+    role: Role;
 }
 
+export type Role = "read_only" | "post" | "invite" | "admin";
+
 export interface Friend {
     publicKey: PubKey;
     addedAt: number; // timestamp when friend was added
@@ -25,4 +132,5 @@ export interface Friend {
 
 export type PubKey = string;
 export type ChatId = string;
+export type MessageId = string;
 export type FriendCode = string; 